@@ -0,0 +1,65 @@
+//! Streaming ZIP archives of DICOM files.
+
+use async_zip::base::write::ZipFileWriter;
+use async_zip::{Compression, ZipEntryBuilder};
+use axum::body::{Bytes, StreamBody};
+use futures::stream::{self, BoxStream, StreamExt};
+use std::path::{Path, PathBuf};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+use tokio_util::io::ReaderStream;
+use tracing::{event, Level};
+
+/// Size of the buffer between the ZIP writer and the response body.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Produce a ZIP archive of the given DICOM files as a response body.
+///
+/// The archive is written as the response is sent, without temporary files.
+/// Each file is named `{series directory}/{file name}`, e.g.
+/// `00005-SAG_T1_MPRAGE-4f9e281/0001-1.3.12.2.1107.5.2.43.166047.2020021012372592892338397.dcm`.
+/// DICOM files do not compress well, so they are stored without compression.
+pub(crate) fn zip_body(
+    files: Vec<PathBuf>,
+) -> StreamBody<BoxStream<'static, std::io::Result<Bytes>>> {
+    let (writer, reader) = tokio::io::duplex(BUFFER_SIZE);
+    let task = tokio::spawn(async move {
+        let mut zip = ZipFileWriter::with_tokio(writer);
+        for path in files {
+            let file = tokio::fs::File::open(&path).await?;
+            let entry = ZipEntryBuilder::new(name_in_archive(&path).into(), Compression::Stored);
+            let mut entry_writer = zip.write_entry_stream(entry).await.map_err(to_io_error)?;
+            futures::io::copy(&mut file.compat(), &mut entry_writer).await?;
+            entry_writer.close().await.map_err(to_io_error)?;
+        }
+        zip.close().await.map_err(to_io_error)?;
+        Ok::<_, std::io::Error>(())
+    });
+    // if writing fails, the response must fail too instead of ending with a truncated archive
+    let outcome = stream::once(async move {
+        match task.await {
+            Ok(Ok(())) => None,
+            Ok(Err(error)) => Some(Err(error)),
+            Err(error) => Some(Err(std::io::Error::new(std::io::ErrorKind::Other, error))),
+        }
+    })
+    .filter_map(|result| async move {
+        if let Some(Err(error)) = &result {
+            event!(Level::ERROR, "Failed to write ZIP archive: {:?}", error);
+        }
+        result
+    });
+    StreamBody::new(ReaderStream::new(reader).chain(outcome).boxed())
+}
+
+fn name_in_archive(path: &Path) -> String {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    if let Some(dir_name) = path.parent().and_then(|p| p.file_name()) {
+        format!("{}/{}", dir_name.to_string_lossy(), file_name)
+    } else {
+        file_name.to_string()
+    }
+}
+
+fn to_io_error(error: async_zip::error::ZipError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, error)
+}
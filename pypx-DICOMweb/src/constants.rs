@@ -1,3 +1,10 @@
-pub(crate) const PATIENT_ID: &str = "00100020";
+pub(crate) const WARNING_ADDITIONAL_RESULTS: &str =
+    "299 pypx-DICOMweb: There are additional results that can be requested";
 
-pub(crate) const MULTIPART_BOUNDARY: &[u8] = b"--BOUNDARY_f46ebe44-9bc9-4eab-9c0d-9dbf5890659e";
+pub(crate) const APPLICATION_DICOM: &str = "application/dicom";
+
+pub(crate) const APPLICATION_DICOM_JSON: &str = "application/dicom+json";
+
+pub(crate) const APPLICATION_ZIP: &str = "application/zip";
+
+pub(crate) const APPLICATION_OCTET_STREAM: &str = "application/octet-stream";
@@ -1,62 +1,295 @@
 //! Helper functions for reading DICOM files.
 
 use crate::errors::FileError;
-use dicom::object::ReadError;
-use dicom::pixeldata::PixelDecoder;
-use serde_json::Value;
+use dicom::core::header::Header;
+use dicom::core::{DicomValue, Tag, VR};
+use dicom::dictionary_std::{tags, uids, StandardDataDictionary};
+use dicom::encoding::transfer_syntax::Codec;
+use dicom::encoding::TransferSyntaxIndex;
+use dicom::object::mem::InMemElement;
+use dicom::object::{DefaultDicomObject, InMemDicomObject, OpenFileOptions, ReadError};
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use serde_json::{json, Map, Value};
 use std::path::{Path, PathBuf};
 
-/// Serialize DICOM file as JSON.
-pub(crate) async fn dicomfile2json(path: PathBuf) -> Result<Value, FileError> {
+/// How binary attributes are presented in DICOM JSON.
+///
+/// https://dicom.nema.org/medical/dicom/current/output/html/part18.html#sect_F.2.7
+#[derive(Clone)]
+pub(crate) struct BulkData {
+    /// The URL of this DICOMweb service, for producing `BulkDataURI` references.
+    pub base_url: String,
+    /// Binary attributes larger than this many bytes are replaced by a `BulkDataURI`.
+    pub threshold: usize,
+}
+
+/// Serialize DICOM file as JSON. Large binary attributes such as PixelData
+/// are replaced by `BulkDataURI` references.
+///
+/// Only the header of the file is read: see [open_file_header].
+pub(crate) async fn dicomfile2json(path: PathBuf, bulkdata: BulkData) -> Result<Value, FileError> {
     let p = path.to_path_buf();
-    tokio::task::spawn_blocking(move || dicom::object::open_file(p))
-        .await
-        .map_err(|error| FileError::Runtime(path.to_path_buf(), error.into()))?
-        .map_err(|error| convert_error(&path, error))
-        .and_then(|dcm| {
-            dicom_json::to_value(dcm).map_err(|error| {
-                FileError::Malformed(
-                    path,
-                    "Could not parse as JSON".to_string(),
-                    Some(error.into()),
-                )
-            })
-        })
+    tokio::task::spawn_blocking(move || {
+        open_file_header(&p).and_then(|dcm| dicom2json(&p, &dcm, pixel_data_vr(&dcm), &bulkdata))
+    })
+    .await
+    .map_err(|error| FileError::Runtime(path, error.into()))?
 }
 
-/// Get a frame (zero-indexed) of a DICOM file.
-pub async fn encode_frame(path: PathBuf, frame: u32) -> Result<Vec<u8>, FileError> {
+/// Serialize the attributes of a DICOM file's data set as JSON, excluding sequences
+/// and binary attributes. Only the header of the file is read.
+pub(crate) async fn dicomfile_attributes(path: PathBuf) -> Result<Map<String, Value>, FileError> {
     let p = path.to_path_buf();
-    tokio::task::spawn_blocking(move || encode_frame_sync(p, frame))
-        .await
-        .map_err(|error| FileError::Runtime(path.to_path_buf(), error.into()))?
+    tokio::task::spawn_blocking(move || {
+        open_file_header(&p)?
+            .iter()
+            .filter(|element| !is_binary(element.vr()))
+            .map(|element| Ok((tag2key(element.tag()), dicom_json::to_value(element)?)))
+            .collect::<Result<_, serde_json::Error>>()
+            .map_err(|error| {
+                FileError::Malformed(p, "Could not parse as JSON".to_string(), Some(error.into()))
+            })
+    })
+    .await
+    .map_err(|error| FileError::Runtime(path, error.into()))?
 }
 
-fn encode_frame_sync(path: PathBuf, frame: u32) -> Result<Vec<u8>, FileError> {
-    let dcm = dicom::object::open_file(&path).map_err(|error| convert_error(&path, error))?;
-    let pixel_data = dcm.decode_pixel_data().map_err(|error| {
-        FileError::Malformed(
-            path.to_path_buf(),
-            "Could not decode pixel data".to_string(),
-            Some(error.into()),
-        )
-    })?;
-    // Previously in commit 4a2646f0260bc72530abb3f163c112cb7e51481b
-    // I was encoding the data as JPEG, which would cause glitches in OHIF.
-    // OHIF seems to have the best support for image/jls and raw DICOM pixel data.
-    pixel_data
-        .frame_data(frame)
-        .map(|data| data.to_vec())
+/// Serialize a DICOM object as JSON.
+///
+/// `pixel_data_vr` should be the VR of PixelData if it was present in the file
+/// but is absent from `dcm`, i.e. when `dcm` was read by [open_file_header].
+///
+/// Bulk data is presented without a `BulkDataURI` if the instance is missing any of
+/// its UIDs, since there is no URL to retrieve it from.
+fn dicom2json(
+    path: &Path,
+    dcm: &DefaultDicomObject,
+    pixel_data_vr: Option<VR>,
+    bulkdata: &BulkData,
+) -> Result<Value, FileError> {
+    let bulkdata_url =
+        instance_url_of(dcm, &bulkdata.base_url).map(|url| format!("{url}/bulkdata/"));
+    meta_to_json(dcm)
+        .and_then(|mut json| {
+            let dataset = dataset_to_json(dcm, bulkdata_url.as_deref(), bulkdata.threshold)?;
+            json.extend(dataset);
+            Ok(json)
+        })
+        .map(|mut json| {
+            if let Some(vr) = pixel_data_vr {
+                let key = tag2key(tags::PIXEL_DATA);
+                let value = bulkdata_json(vr, bulkdata_url.as_deref(), &key);
+                json.insert(key, value);
+            }
+            Value::Object(json)
+        })
         .map_err(|error| {
             FileError::Malformed(
-                path,
-                format!("Failed to get pixel data at frame={frame}"),
+                path.to_path_buf(),
+                "Could not parse as JSON".to_string(),
                 Some(error.into()),
             )
         })
 }
 
-fn convert_error(path: &Path, error: ReadError) -> FileError {
+/// Read a DICOM file up to, but excluding, its PixelData. PixelData is usually the last
+/// attribute of a file and by far the largest. Attributes after PixelData (which are rare,
+/// e.g. DataSetTrailingPadding) are not read.
+fn open_file_header(path: &Path) -> Result<DefaultDicomObject, FileError> {
+    OpenFileOptions::new()
+        .read_until(tags::PIXEL_DATA)
+        .open_file(path)
+        .map_err(|error| convert_error(path, error))
+}
+
+/// The VR of the PixelData of a DICOM object read by [open_file_header], which is
+/// not read from the file, hence is determined by the rules of PS3.5 section A:
+/// PixelData is OB if encapsulated, OW in Implicit VR Little Endian, and otherwise
+/// OW if BitsAllocated is greater than 8, OB if not.
+///
+/// Returns `None` if the object has no Image Pixel module (i.e. no BitsAllocated),
+/// in which case it is assumed not to have PixelData.
+fn pixel_data_vr(dcm: &DefaultDicomObject) -> Option<VR> {
+    let bits_allocated = dcm
+        .element(tags::BITS_ALLOCATED)
+        .ok()
+        .and_then(|element| element.to_int::<u16>().ok())?;
+    let transfer_syntax = dcm.meta().transfer_syntax().trim_end_matches('\0');
+    let encapsulated = TransferSyntaxRegistry
+        .get(transfer_syntax)
+        .is_some_and(|ts| matches!(ts.codec(), Codec::EncapsulatedPixelData(..)));
+    if encapsulated {
+        Some(VR::OB)
+    } else if transfer_syntax == uids::IMPLICIT_VR_LITTLE_ENDIAN || bits_allocated > 8 {
+        Some(VR::OW)
+    } else {
+        Some(VR::OB)
+    }
+}
+
+/// Get the raw bytes of an attribute of a DICOM file.
+///
+/// The attribute is identified by a path of tags and sequence item indexes (starting from 0),
+/// e.g. `7FE00010` or `00089215/0/00089219`. Returns `None` if there is no such attribute,
+/// or if it is not of a binary VR.
+pub async fn read_bulkdata(path: PathBuf, tag_path: String) -> Result<Option<Vec<u8>>, FileError> {
+    let p = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        dicom::object::open_file(&p)
+            .map(|dcm| find_bulkdata(&dcm, &tag_path))
+            .map_err(|error| convert_error(&p, error))
+    })
+    .await
+    .map_err(|error| FileError::Runtime(path, error.into()))?
+}
+
+/// URL of the instance in the DICOMweb service at `base_url`.
+fn instance_url_of(dcm: &DefaultDicomObject, base_url: &str) -> Option<String> {
+    let uid_of = |tag| {
+        dcm.element(tag)
+            .ok()
+            .and_then(|element| element.to_str().ok())
+            .map(|uid| uid.trim_end_matches(['\0', ' ']).to_string())
+    };
+    let study = uid_of(tags::STUDY_INSTANCE_UID)?;
+    let series = uid_of(tags::SERIES_INSTANCE_UID)?;
+    let instance = uid_of(tags::SOP_INSTANCE_UID)?;
+    Some(format!(
+        "{base_url}/studies/{study}/series/{series}/instances/{instance}"
+    ))
+}
+
+/// Serialize the file meta group the same way as [dicom_json::to_value] does.
+fn meta_to_json(dcm: &DefaultDicomObject) -> Result<Map<String, Value>, serde_json::Error> {
+    dcm.meta()
+        .to_element_iter()
+        .filter_map(|element| match element.value() {
+            DicomValue::Primitive(value) => Some(InMemElement::<StandardDataDictionary>::new(
+                element.tag(),
+                element.vr(),
+                value.clone(),
+            )),
+            _ => None,
+        })
+        .map(|element| Ok((tag2key(element.tag()), dicom_json::to_value(&element)?)))
+        .collect()
+}
+
+/// Serialize a data set, replacing bulk data with `BulkDataURI` references.
+///
+/// `bulkdata_url` is the URL prefix of the data set's attributes, ending with `/`.
+fn dataset_to_json(
+    obj: &InMemDicomObject,
+    bulkdata_url: Option<&str>,
+    threshold: usize,
+) -> Result<Map<String, Value>, serde_json::Error> {
+    obj.iter()
+        .map(|element| {
+            let key = tag2key(element.tag());
+            let value = if is_bulkdata(element, threshold) {
+                bulkdata_json(element.vr(), bulkdata_url, &key)
+            } else if let DicomValue::Sequence(seq) = element.value() {
+                let items = seq
+                    .items()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, item)| {
+                        let item_url = bulkdata_url.map(|url| format!("{url}{key}/{i}/"));
+                        dataset_to_json(item, item_url.as_deref(), threshold).map(Value::Object)
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                json!({ "vr": "SQ", "Value": items })
+            } else {
+                dicom_json::to_value(element)?
+            };
+            Ok((key, value))
+        })
+        .collect()
+}
+
+/// Bulk data attribute, which is only a VR if there is no `bulkdata_url`.
+fn bulkdata_json(vr: VR, bulkdata_url: Option<&str>, key: &str) -> Value {
+    match bulkdata_url {
+        Some(url) => json!({ "vr": vr.to_string(), "BulkDataURI": format!("{url}{key}") }),
+        None => json!({ "vr": vr.to_string() }),
+    }
+}
+
+/// Whether an attribute should be presented as a `BulkDataURI`: pixel data, overlay data,
+/// encapsulated documents, and any other binary value larger than `threshold`.
+fn is_bulkdata(element: &InMemElement, threshold: usize) -> bool {
+    let tag = element.tag();
+    match element.value() {
+        DicomValue::PixelSequence(_) => true,
+        DicomValue::Sequence(_) => false,
+        DicomValue::Primitive(value) => {
+            matches!(
+                element.vr(),
+                VR::OB | VR::OD | VR::OF | VR::OL | VR::OV | VR::OW | VR::UN
+            ) && (BULKDATA_TAGS.contains(&tag)
+                || (tag.group() & 0xFF00 == 0x6000 && tag.element() == 0x3000)
+                || value.calculate_byte_len() > threshold)
+        }
+    }
+}
+
+/// Binary attributes which are always presented as a `BulkDataURI`, regardless of size.
+const BULKDATA_TAGS: [Tag; 5] = [
+    tags::PIXEL_DATA,
+    tags::FLOAT_PIXEL_DATA,
+    tags::DOUBLE_FLOAT_PIXEL_DATA,
+    tags::ENCAPSULATED_DOCUMENT,
+    tags::WAVEFORM_DATA,
+];
+
+fn find_bulkdata(obj: &InMemDicomObject, tag_path: &str) -> Option<Vec<u8>> {
+    let (key, rest) = match tag_path.split_once('/') {
+        Some((key, rest)) => (key, Some(rest)),
+        None => (tag_path, None),
+    };
+    let tag = parse_tag(key)?;
+    let element = obj.get(tag)?;
+    match (element.value(), rest) {
+        (DicomValue::Sequence(seq), Some(rest)) => {
+            let (index, rest) = rest.split_once('/')?;
+            let item = seq.items().get(index.parse::<usize>().ok()?)?;
+            find_bulkdata(item, rest)
+        }
+        (DicomValue::PixelSequence(seq), None) => Some(seq.fragments().concat()),
+        (DicomValue::Primitive(value), None) if is_bulkdata(element, 0) => {
+            Some(value.to_bytes().to_vec())
+        }
+        _ => None,
+    }
+}
+
+fn tag2key(tag: Tag) -> String {
+    format!("{:04X}{:04X}", tag.group(), tag.element())
+}
+
+fn parse_tag(key: &str) -> Option<Tag> {
+    if key.len() != 8 {
+        return None;
+    }
+    let group = u16::from_str_radix(&key[..4], 16).ok()?;
+    let element = u16::from_str_radix(&key[4..], 16).ok()?;
+    Some(Tag(group, element))
+}
+
+/// Whether values of the VR are not (or do not fit in) a string.
+pub(crate) fn is_binary(vr: VR) -> bool {
+    matches!(
+        vr,
+        VR::OB | VR::OD | VR::OF | VR::OL | VR::OV | VR::OW | VR::UN | VR::SQ
+    )
+}
+
+/// Whether a string looks like a UID, so that it is safe to use in a file name.
+pub(crate) fn is_uid(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_digit() || c == '.') && !s.contains("..")
+}
+
+pub(crate) fn convert_error(path: &Path, error: ReadError) -> FileError {
     match error {
         ReadError::OpenFile {
             filename, source, ..
@@ -74,3 +307,174 @@ fn convert_error(path: &Path, error: ReadError) -> FileError {
         ),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dicom::core::value::{DataSetSequence, PixelFragmentSequence};
+    use dicom::core::PrimitiveValue;
+    use dicom::object::meta::FileMetaTableBuilder;
+    use rstest::*;
+
+    #[fixture]
+    fn example_dataset() -> InMemDicomObject {
+        let icon = InMemDicomObject::from_element_iter([InMemElement::new(
+            tags::PIXEL_DATA,
+            VR::OB,
+            PrimitiveValue::from(vec![7u8; 4]),
+        )]);
+        InMemDicomObject::from_element_iter([
+            InMemElement::new(
+                tags::SOP_INSTANCE_UID,
+                VR::UI,
+                PrimitiveValue::from("1.2.3.4"),
+            ),
+            InMemElement::new(
+                tags::ICON_IMAGE_SEQUENCE,
+                VR::SQ,
+                DataSetSequence::from(vec![icon]),
+            ),
+            InMemElement::new(
+                Tag(0x0009, 0x1001),
+                VR::OB,
+                PrimitiveValue::from(vec![1u8, 2, 3, 4]),
+            ),
+            InMemElement::new(
+                Tag(0x0009, 0x1002),
+                VR::OB,
+                PrimitiveValue::from(vec![0u8; 64]),
+            ),
+            InMemElement::new(
+                tags::PIXEL_DATA,
+                VR::OW,
+                PrimitiveValue::U16(vec![0u16; 16].into()),
+            ),
+        ])
+    }
+
+    #[rstest]
+    fn test_dataset_to_json(example_dataset: InMemDicomObject) {
+        let actual = dataset_to_json(&example_dataset, Some("http://x/bulkdata/"), 16).unwrap();
+        let expected = json!({
+            "00080018": { "vr": "UI", "Value": ["1.2.3.4"] },
+            "00880200": { "vr": "SQ", "Value": [{
+                "7FE00010": { "vr": "OB", "BulkDataURI": "http://x/bulkdata/00880200/0/7FE00010" }
+            }]},
+            "00091001": { "vr": "OB", "InlineBinary": "AQIDBA==" },
+            "00091002": { "vr": "OB", "BulkDataURI": "http://x/bulkdata/00091002" },
+            "7FE00010": { "vr": "OW", "BulkDataURI": "http://x/bulkdata/7FE00010" },
+        });
+        assert_eq!(Value::Object(actual), expected);
+    }
+
+    #[rstest]
+    #[case("7FE00010", Some(vec![0u8; 32]))]
+    #[case("00880200/0/7FE00010", Some(vec![7u8; 4]))]
+    #[case("00091002", Some(vec![0u8; 64]))]
+    #[case("00080018", None)]
+    #[case("00880200/1/7FE00010", None)]
+    #[case("00880200", None)]
+    #[case("not a tag", None)]
+    fn test_find_bulkdata(
+        example_dataset: InMemDicomObject,
+        #[case] tag_path: &str,
+        #[case] expected: Option<Vec<u8>>,
+    ) {
+        assert_eq!(find_bulkdata(&example_dataset, tag_path), expected);
+    }
+
+    #[rstest]
+    #[case(
+        uids::EXPLICIT_VR_LITTLE_ENDIAN,
+        Some(16),
+        Some(native_pixel_data(VR::OW))
+    )]
+    #[case(
+        uids::EXPLICIT_VR_LITTLE_ENDIAN,
+        Some(8),
+        Some(native_pixel_data(VR::OB))
+    )]
+    #[case(
+        uids::IMPLICIT_VR_LITTLE_ENDIAN,
+        Some(8),
+        Some(native_pixel_data(VR::OW))
+    )]
+    #[case(uids::JPEG_BASELINE8_BIT, Some(8), Some(encapsulated_pixel_data()))]
+    #[case(uids::EXPLICIT_VR_LITTLE_ENDIAN, None, None)]
+    fn test_header_only_json_is_identical(
+        example_dataset: InMemDicomObject,
+        #[case] transfer_syntax: &str,
+        #[case] bits_allocated: Option<u16>,
+        #[case] pixel_data: Option<InMemElement>,
+    ) {
+        let mut obj = example_dataset;
+        obj.remove_element(tags::PIXEL_DATA);
+        obj.put(InMemElement::new(
+            tags::STUDY_INSTANCE_UID,
+            VR::UI,
+            PrimitiveValue::from("1.2"),
+        ));
+        obj.put(InMemElement::new(
+            tags::SERIES_INSTANCE_UID,
+            VR::UI,
+            PrimitiveValue::from("1.2.3"),
+        ));
+        if let Some(bits_allocated) = bits_allocated {
+            obj.put(InMemElement::new(
+                tags::BITS_ALLOCATED,
+                VR::US,
+                PrimitiveValue::from(bits_allocated),
+            ));
+        }
+        if let Some(pixel_data) = pixel_data {
+            obj.put(pixel_data);
+        }
+        let meta = FileMetaTableBuilder::new()
+            .transfer_syntax(transfer_syntax)
+            .media_storage_sop_class_uid(uids::SECONDARY_CAPTURE_IMAGE_STORAGE)
+            .media_storage_sop_instance_uid("1.2.3.4");
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("header.dcm");
+        obj.with_meta(meta).unwrap().write_to_file(&path).unwrap();
+
+        let bulkdata = BulkData {
+            base_url: "http://x".to_string(),
+            threshold: 16,
+        };
+        let full = dicom::object::open_file(&path).unwrap();
+        let expected = dicom2json(&path, &full, None, &bulkdata).unwrap();
+        let header = open_file_header(&path).unwrap();
+        let actual = dicom2json(&path, &header, pixel_data_vr(&header), &bulkdata).unwrap();
+
+        assert!(header.get(tags::PIXEL_DATA).is_none());
+        assert_eq!(actual, expected);
+    }
+
+    #[rstest]
+    fn test_dicom2json_without_uids(example_dataset: InMemDicomObject) {
+        let meta = FileMetaTableBuilder::new()
+            .transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN)
+            .media_storage_sop_class_uid(uids::SECONDARY_CAPTURE_IMAGE_STORAGE)
+            .media_storage_sop_instance_uid("1.2.3.4");
+        let dcm = example_dataset.with_meta(meta).unwrap();
+        let bulkdata = BulkData {
+            base_url: "http://x".to_string(),
+            threshold: 16,
+        };
+        let json = dicom2json(Path::new("x.dcm"), &dcm, None, &bulkdata).unwrap();
+        assert_eq!(json["7FE00010"], json!({ "vr": "OW" }));
+        assert_eq!(
+            json["00880200"]["Value"][0]["7FE00010"],
+            json!({ "vr": "OB" })
+        );
+    }
+
+    fn native_pixel_data(vr: VR) -> InMemElement {
+        InMemElement::new(tags::PIXEL_DATA, vr, PrimitiveValue::from(vec![9u8; 64]))
+    }
+
+    fn encapsulated_pixel_data() -> InMemElement {
+        let fragments = PixelFragmentSequence::new(vec![], vec![vec![0xFFu8, 0xD8, 0xFF, 0xD9]]);
+        InMemElement::new(tags::PIXEL_DATA, VR::OB, DicomValue::from(fragments))
+    }
+}
@@ -40,3 +40,49 @@ impl FileError {
 #[derive(thiserror::Error, Debug)]
 #[error("Error reading directory (1:?): {0:?}")]
 pub struct ReadDirError(pub(crate) PathBuf, pub(crate) std::io::ErrorKind);
+
+impl From<ReadDirError> for FileError {
+    fn from(ReadDirError(path, kind): ReadDirError) -> Self {
+        match kind {
+            std::io::ErrorKind::NotFound => FileError::NotFound(path),
+            _ => FileError::ParentDirNotReadable(path, kind),
+        }
+    }
+}
+
+/// Error building or reading the SQLite index.
+#[derive(thiserror::Error, Debug)]
+pub enum IndexError {
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Index {0:?} has version {1}, please rebuild it by running `pypx_dicomweb index`")]
+    Version(PathBuf, i32),
+    #[error("Indexed metadata is malformed: {0:?}")]
+    Malformed(serde_json::Error),
+    #[error("IO error ({1:?}): {0:?}")]
+    IO(PathBuf, std::io::ErrorKind),
+    #[error("Runtime error while reading index -- {0:?}")]
+    Runtime(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// Error with a query parameter of a QIDO-RS request.
+#[derive(thiserror::Error, Debug)]
+#[error("Invalid query parameter {0:?}: {1}")]
+pub struct InvalidQueryParameter(pub String, pub &'static str);
+
+/// Error storing an uploaded DICOM file (STOW-RS).
+#[derive(thiserror::Error, Debug)]
+pub enum StoreError {
+    #[error("Cannot read DICOM file: {0}")]
+    Unreadable(#[source] dicom::object::ReadError),
+    #[error("Missing attribute {0}")]
+    MissingAttribute(&'static str),
+    #[error("Invalid {0}: {1:?}")]
+    InvalidUid(&'static str, String),
+    #[error("Error writing file ({1:?}): {0:?}")]
+    IO(PathBuf, std::io::ErrorKind),
+    #[error(transparent)]
+    Log(#[from] pypx::WriteError),
+    #[error("Runtime error while writing {0:?} -- {1:?}")]
+    Runtime(PathBuf, Box<dyn std::error::Error + Send + Sync>),
+}
@@ -0,0 +1,425 @@
+//! Retrieval of frames with transfer syntax negotiation.
+//!
+//! https://dicom.nema.org/medical/dicom/current/output/html/part18.html#sect_8.7.3.5
+
+use crate::constants::APPLICATION_OCTET_STREAM;
+use crate::dicom::convert_error;
+use crate::errors::FileError;
+use dicom::core::DicomValue;
+use dicom::dictionary_std::{tags, uids};
+use dicom::encoding::TransferSyntaxIndex;
+use dicom::object::DefaultDicomObject;
+use dicom::pixeldata::{PixelDecoder, PlanarConfiguration};
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use image::codecs::jpeg::JpegEncoder;
+use image::ColorType;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// An acceptable media type of frames, from the `Accept` header of a request.
+#[derive(Debug, PartialEq)]
+pub(crate) struct AcceptedType {
+    media_type: String,
+    /// The `transfer-syntax` parameter, which may be `*`.
+    transfer_syntax: Option<String>,
+}
+
+impl AcceptedType {
+    /// Parse values of the `Accept` header, e.g.
+    /// `multipart/related; type="image/jls"; transfer-syntax=1.2.840.10008.1.2.4.80`.
+    /// If there are none, any media type is acceptable.
+    pub fn parse_all<'a>(accept: impl Iterator<Item = &'a str>) -> Vec<Self> {
+        let accepted: Vec<_> = accept
+            .flat_map(|value| value.split(','))
+            .filter_map(|media_range| {
+                let mut parts = media_range.split(';').map(|s| s.trim());
+                let media_type = parts.next().filter(|s| !s.is_empty())?.to_lowercase();
+                let params: HashMap<_, _> = parts
+                    .filter_map(|param| param.split_once('='))
+                    .map(|(key, value)| (key.trim().to_lowercase(), value.trim().trim_matches('"')))
+                    .collect();
+                let media_type = match media_type.as_str() {
+                    "multipart/related" => params
+                        .get("type")
+                        .map(|t| t.to_lowercase())
+                        .unwrap_or_else(|| APPLICATION_OCTET_STREAM.to_string()),
+                    "*/*" | "multipart/*" => ANY.to_string(),
+                    _ => media_type,
+                };
+                let transfer_syntax = params.get("transfer-syntax").map(|s| s.to_string());
+                Some(Self {
+                    media_type,
+                    transfer_syntax,
+                })
+            })
+            .collect();
+        if accepted.is_empty() {
+            vec![Self {
+                media_type: ANY.to_string(),
+                transfer_syntax: None,
+            }]
+        } else {
+            accepted
+        }
+    }
+
+    /// Whether frames of the given transfer syntax are acceptable.
+    fn accepts(&self, transfer_syntax: &str) -> bool {
+        if self.media_type == ANY {
+            return self
+                .transfer_syntax
+                .as_ref()
+                .map(|ts| ts == "*" || ts == transfer_syntax)
+                .unwrap_or(true);
+        }
+        if media_type_of(transfer_syntax) != Some(self.media_type.as_str()) {
+            return false;
+        }
+        match self.transfer_syntax.as_deref() {
+            Some("*") => true,
+            Some(ts) => ts == transfer_syntax,
+            None => default_transfer_syntax_of(&self.media_type) == Some(transfer_syntax),
+        }
+    }
+}
+
+const ANY: &str = "*/*";
+/// Retired, but files in this transfer syntax may still be around.
+const EXPLICIT_VR_BIG_ENDIAN: &str = "1.2.840.10008.1.2.2";
+
+/// Media type of frames in the given transfer syntax.
+///
+/// https://dicom.nema.org/medical/dicom/current/output/html/part18.html#table_8.7.3-2
+fn media_type_of(transfer_syntax: &str) -> Option<&'static str> {
+    match transfer_syntax {
+        uids::IMPLICIT_VR_LITTLE_ENDIAN
+        | uids::EXPLICIT_VR_LITTLE_ENDIAN
+        | uids::DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN
+        | EXPLICIT_VR_BIG_ENDIAN => Some(APPLICATION_OCTET_STREAM),
+        uids::JPEG_BASELINE8_BIT
+        | uids::JPEG_EXTENDED12_BIT
+        | uids::JPEG_LOSSLESS
+        | uids::JPEG_LOSSLESS_SV1 => Some("image/jpeg"),
+        uids::JPEGLS_LOSSLESS | uids::JPEGLS_NEAR_LOSSLESS => Some("image/jls"),
+        uids::JPEG2000_LOSSLESS | uids::JPEG2000 => Some("image/jp2"),
+        uids::JPEG2000MC_LOSSLESS | uids::JPEG2000MC => Some("image/jpx"),
+        uids::RLE_LOSSLESS => Some("image/dicom-rle"),
+        _ => None,
+    }
+}
+
+/// The transfer syntax of a media type when the `transfer-syntax` parameter is absent.
+fn default_transfer_syntax_of(media_type: &str) -> Option<&'static str> {
+    match media_type {
+        APPLICATION_OCTET_STREAM => Some(uids::EXPLICIT_VR_LITTLE_ENDIAN),
+        "image/jpeg" => Some(uids::JPEG_BASELINE8_BIT),
+        "image/jls" => Some(uids::JPEGLS_LOSSLESS),
+        "image/jp2" => Some(uids::JPEG2000_LOSSLESS),
+        "image/jpx" => Some(uids::JPEG2000MC_LOSSLESS),
+        "image/dicom-rle" => Some(uids::RLE_LOSSLESS),
+        _ => None,
+    }
+}
+
+/// How frames are produced.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Encoding {
+    /// Compressed frames are sent as they are stored, without decoding.
+    AsStored,
+    /// Pixel data is decoded (if necessary) and sent as native pixel data.
+    Native,
+    /// Pixel data is decoded (if necessary) and encoded as baseline JPEG.
+    JpegBaseline,
+}
+
+/// What can be done with the pixel data of a DICOM file.
+struct Capabilities<'a> {
+    stored_transfer_syntax: &'a str,
+    /// Stored frames can be sent without decoding.
+    as_stored: bool,
+    decodable: bool,
+    /// Decoded frames can be encoded as baseline JPEG.
+    jpeg_encodable: bool,
+}
+
+/// Choose how to produce frames, preferring the first acceptable type, and preferring to
+/// send frames as they are stored. Returns `None` if none of the types are acceptable.
+fn negotiate(accepted: &[AcceptedType], c: &Capabilities) -> Option<(Encoding, &'static str)> {
+    let stored = TransferSyntaxRegistry
+        .get(c.stored_transfer_syntax)
+        .map(|ts| ts.uid());
+    let stored_is_native = stored.and_then(media_type_of) == Some(APPLICATION_OCTET_STREAM);
+    let candidates = [
+        stored
+            .filter(|_| stored_is_native && c.decodable)
+            .map(|ts| (Encoding::Native, ts)),
+        stored
+            .filter(|_| !stored_is_native && c.as_stored)
+            .map(|ts| (Encoding::AsStored, ts)),
+        Some((Encoding::Native, uids::EXPLICIT_VR_LITTLE_ENDIAN)).filter(|_| c.decodable),
+        Some((Encoding::Native, uids::IMPLICIT_VR_LITTLE_ENDIAN)).filter(|_| c.decodable),
+        Some((Encoding::JpegBaseline, uids::JPEG_BASELINE8_BIT)).filter(|_| c.jpeg_encodable),
+    ];
+    accepted.iter().find_map(|accepted| {
+        candidates
+            .iter()
+            .flatten()
+            .find(|(_, ts)| accepted.accepts(ts))
+            .copied()
+    })
+}
+
+/// Result of [retrieve_frames].
+pub(crate) enum Frames {
+    /// The requested frames, which all have the same `Content-Type`.
+    Found {
+        content_type: String,
+        frames: Vec<Vec<u8>>,
+    },
+    /// Some of the requested frames do not exist.
+    NotFound,
+    /// The frames cannot be produced in any of the acceptable media types.
+    NotAcceptable,
+}
+
+/// Get frames (zero-indexed) of a DICOM file in one of the accepted media types.
+/// Pixel data is decoded at most once.
+pub(crate) async fn retrieve_frames(
+    path: PathBuf,
+    frames: Vec<u32>,
+    accepted: Vec<AcceptedType>,
+) -> Result<Frames, FileError> {
+    let p = path.to_path_buf();
+    tokio::task::spawn_blocking(move || retrieve_frames_sync(&p, &frames, &accepted))
+        .await
+        .map_err(|error| FileError::Runtime(path, error.into()))?
+}
+
+fn retrieve_frames_sync(
+    path: &Path,
+    frames: &[u32],
+    accepted: &[AcceptedType],
+) -> Result<Frames, FileError> {
+    let dcm = dicom::object::open_file(path).map_err(|error| convert_error(path, error))?;
+    let get_int = |tag| dcm.element(tag).ok().and_then(|e| e.to_int::<u32>().ok());
+    let number_of_frames = get_int(tags::NUMBER_OF_FRAMES).unwrap_or(1);
+    if frames.iter().any(|frame| *frame >= number_of_frames) {
+        return Ok(Frames::NotFound);
+    }
+    let stored_transfer_syntax = dcm.meta().transfer_syntax();
+    let stored_frames = stored_frames_of(&dcm, number_of_frames);
+    let decodable = TransferSyntaxRegistry
+        .get(stored_transfer_syntax)
+        .map(|ts| ts.can_decode_all())
+        .unwrap_or(false);
+    let capabilities = Capabilities {
+        stored_transfer_syntax,
+        as_stored: stored_frames.is_some(),
+        decodable,
+        jpeg_encodable: decodable
+            && get_int(tags::BITS_ALLOCATED) == Some(8)
+            && matches!(get_int(tags::SAMPLES_PER_PIXEL), Some(1 | 3)),
+    };
+    let (encoding, transfer_syntax) = if let Some(negotiated) = negotiate(accepted, &capabilities) {
+        negotiated
+    } else {
+        return Ok(Frames::NotAcceptable);
+    };
+    let media_type = media_type_of(transfer_syntax).unwrap_or(APPLICATION_OCTET_STREAM);
+    let content_type = format!("{media_type};transfer-syntax={transfer_syntax}");
+
+    let malformed = |reason: String, error: Box<dyn std::error::Error + Send + Sync>| {
+        FileError::Malformed(path.to_path_buf(), reason, Some(error))
+    };
+    let data = if let (Encoding::AsStored, Some(mut stored_frames)) = (encoding, stored_frames) {
+        frames
+            .iter()
+            .map(|frame| std::mem::take(&mut stored_frames[*frame as usize]))
+            .collect()
+    } else {
+        let pixel_data = dcm
+            .decode_pixel_data()
+            .map_err(|error| malformed("Could not decode pixel data".to_string(), error.into()))?;
+        frames
+            .iter()
+            .map(|frame| {
+                let data = pixel_data.frame_data(*frame).map_err(|error| {
+                    malformed(
+                        format!("Failed to get pixel data at frame={frame}"),
+                        error.into(),
+                    )
+                })?;
+                if encoding == Encoding::JpegBaseline {
+                    encode_jpeg(
+                        data,
+                        pixel_data.columns(),
+                        pixel_data.rows(),
+                        pixel_data.samples_per_pixel(),
+                        pixel_data.planar_configuration(),
+                    )
+                    .map_err(|error| malformed("Could not encode JPEG".to_string(), error.into()))
+                } else {
+                    Ok(data.to_vec())
+                }
+            })
+            .collect::<Result<_, _>>()?
+    };
+    Ok(Frames::Found {
+        content_type,
+        frames: data,
+    })
+}
+
+/// Split encapsulated pixel data into frames without decoding.
+/// Returns `None` if the pixel data is native, or if it is not clear which fragments
+/// belong to which frame.
+fn stored_frames_of(dcm: &DefaultDicomObject, number_of_frames: u32) -> Option<Vec<Vec<u8>>> {
+    if let DicomValue::PixelSequence(seq) = dcm.element(tags::PIXEL_DATA).ok()?.value() {
+        group_fragments(seq.fragments(), seq.offset_table(), number_of_frames)
+    } else {
+        None
+    }
+}
+
+/// Group fragments into frames, using the basic offset table if necessary.
+fn group_fragments(
+    fragments: &[Vec<u8>],
+    offset_table: &[u32],
+    number_of_frames: u32,
+) -> Option<Vec<Vec<u8>>> {
+    let number_of_frames = number_of_frames as usize;
+    if number_of_frames == 1 {
+        Some(vec![fragments.concat()])
+    } else if fragments.len() == number_of_frames {
+        Some(fragments.to_vec())
+    } else if offset_table.len() == number_of_frames {
+        // offsets are relative to the first byte of the first fragment's item tag,
+        // and each fragment item has an 8 byte header
+        let mut frames = vec![Vec::new(); number_of_frames];
+        let mut position = 0u64;
+        for fragment in fragments {
+            let frame = offset_table
+                .iter()
+                .rposition(|offset| *offset as u64 <= position)?;
+            frames[frame].extend_from_slice(fragment);
+            position += 8 + fragment.len() as u64;
+        }
+        Some(frames)
+    } else {
+        None
+    }
+}
+
+fn encode_jpeg(
+    data: &[u8],
+    columns: u32,
+    rows: u32,
+    samples_per_pixel: u16,
+    planar_configuration: PlanarConfiguration,
+) -> image::ImageResult<Vec<u8>> {
+    let mut buf = Vec::new();
+    let mut encoder = JpegEncoder::new_with_quality(&mut buf, 95);
+    if samples_per_pixel == 1 {
+        encoder.encode(data, columns, rows, ColorType::L8)?;
+    } else if planar_configuration == PlanarConfiguration::PixelFirst {
+        let n = (columns * rows) as usize;
+        let interleaved: Vec<u8> = (0..n * 3).map(|i| data[(i % 3) * n + i / 3]).collect();
+        encoder.encode(&interleaved, columns, rows, ColorType::Rgb8)?;
+    } else {
+        encoder.encode(data, columns, rows, ColorType::Rgb8)?;
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case(
+        &["multipart/related; type=\"image/jls\"; transfer-syntax=1.2.840.10008.1.2.4.80"],
+        &[("image/jls", Some("1.2.840.10008.1.2.4.80"))]
+    )]
+    #[case(
+        &["multipart/related; type=application/octet-stream; transfer-syntax=*, multipart/related;type=image/jpeg"],
+        &[("application/octet-stream", Some("*")), ("image/jpeg", None)]
+    )]
+    #[case(&["*/*"], &[("*/*", None)])]
+    #[case(&[], &[("*/*", None)])]
+    fn test_parse_accept(#[case] accept: &[&str], #[case] expected: &[(&str, Option<&str>)]) {
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(media_type, ts)| AcceptedType {
+                media_type: media_type.to_string(),
+                transfer_syntax: ts.map(|s| s.to_string()),
+            })
+            .collect();
+        assert_eq!(AcceptedType::parse_all(accept.iter().copied()), expected)
+    }
+
+    #[rstest]
+    // native is sent as stored
+    #[case("*/*", uids::IMPLICIT_VR_LITTLE_ENDIAN, true, Some((Encoding::Native, uids::IMPLICIT_VR_LITTLE_ENDIAN)))]
+    #[case("multipart/related; type=application/octet-stream", uids::IMPLICIT_VR_LITTLE_ENDIAN, true, Some((Encoding::Native, uids::EXPLICIT_VR_LITTLE_ENDIAN)))]
+    // compressed is sent as stored when acceptable
+    #[case("*/*", uids::JPEGLS_LOSSLESS, false, Some((Encoding::AsStored, uids::JPEGLS_LOSSLESS)))]
+    #[case("multipart/related; type=\"image/jls\"", uids::JPEGLS_LOSSLESS, false, Some((Encoding::AsStored, uids::JPEGLS_LOSSLESS)))]
+    #[case("multipart/related; type=\"image/jls\"; transfer-syntax=*", uids::JPEGLS_NEAR_LOSSLESS, false, Some((Encoding::AsStored, uids::JPEGLS_NEAR_LOSSLESS)))]
+    #[case("multipart/related; type=\"image/jpeg\"; transfer-syntax=*", uids::JPEG_LOSSLESS_SV1, true, Some((Encoding::AsStored, uids::JPEG_LOSSLESS_SV1)))]
+    // compressed is transcoded when necessary
+    #[case("multipart/related; type=application/octet-stream; transfer-syntax=*", uids::JPEG_LOSSLESS_SV1, true, Some((Encoding::Native, uids::EXPLICIT_VR_LITTLE_ENDIAN)))]
+    #[case("multipart/related; type=\"image/jls\", multipart/related; type=application/octet-stream", uids::JPEG_BASELINE8_BIT, true, Some((Encoding::Native, uids::EXPLICIT_VR_LITTLE_ENDIAN)))]
+    #[case("multipart/related; type=\"image/jpeg\"", uids::EXPLICIT_VR_LITTLE_ENDIAN, true, Some((Encoding::JpegBaseline, uids::JPEG_BASELINE8_BIT)))]
+    // nothing acceptable
+    #[case(
+        "multipart/related; type=application/octet-stream",
+        uids::JPEGLS_LOSSLESS,
+        false,
+        None
+    )]
+    #[case(
+        "multipart/related; type=\"image/jp2\"",
+        uids::EXPLICIT_VR_LITTLE_ENDIAN,
+        true,
+        None
+    )]
+    #[case(
+        "multipart/related; type=\"image/jls\"",
+        uids::JPEGLS_NEAR_LOSSLESS,
+        false,
+        None
+    )]
+    fn test_negotiate(
+        #[case] accept: &str,
+        #[case] stored_transfer_syntax: &str,
+        #[case] decodable: bool,
+        #[case] expected: Option<(Encoding, &str)>,
+    ) {
+        let accepted = AcceptedType::parse_all(std::iter::once(accept));
+        let capabilities = Capabilities {
+            stored_transfer_syntax,
+            as_stored: media_type_of(stored_transfer_syntax) != Some(APPLICATION_OCTET_STREAM),
+            decodable,
+            jpeg_encodable: decodable,
+        };
+        assert_eq!(negotiate(&accepted, &capabilities), expected)
+    }
+
+    #[rstest]
+    #[case(vec![vec![1, 2], vec![3]], &[], 1, Some(vec![vec![1, 2, 3]]))]
+    #[case(vec![vec![1, 2], vec![3]], &[], 2, Some(vec![vec![1, 2], vec![3]]))]
+    #[case(vec![vec![1, 2], vec![3], vec![4, 5]], &[0, 18], 2, Some(vec![vec![1, 2, 3], vec![4, 5]]))]
+    #[case(vec![vec![1, 2], vec![3], vec![4, 5]], &[], 2, None)]
+    fn test_group_fragments(
+        #[case] fragments: Vec<Vec<u8>>,
+        #[case] offset_table: &[u32],
+        #[case] number_of_frames: u32,
+        #[case] expected: Option<Vec<Vec<u8>>>,
+    ) {
+        assert_eq!(
+            group_fragments(&fragments, offset_table, number_of_frames),
+            expected
+        )
+    }
+}
@@ -0,0 +1,1212 @@
+//! Optional SQLite index of the studies, series and instances of a `pypx` log directory.
+//!
+//! Answering a QIDO-RS query from the log directory means reading every
+//! `log/studyData/{StudyInstanceUID}-meta.json` file, the series files of the matching
+//! studies and the `log/seriesData/{SeriesInstanceUID}-img` directories of their series,
+//! which is slow for large archives. The index has a table for each of patients, studies,
+//! series and instances, so that matching, counting and pagination are done by SQL.
+//! It is built by running `pypx_dicomweb index`.
+//!
+//! `pypx` rewrites `log/studyData/{StudyInstanceUID}-meta.json` whenever it receives an
+//! instance of a study. Before a query, the index is synchronized with the log directory
+//! (at most once every [SYNC_INTERVAL]): a study is read again, with its series, instances
+//! and patient, if the modification time of this file is not the one recorded in the
+//! index, and removed if the file was removed. Instances stored by STOW-RS are added to
+//! the index right away.
+
+use crate::errors::{FileError, IndexError};
+use crate::pypx_reader::{InstanceFile, Study, StudySummary};
+use crate::qido::{Page, Pagination, QidoQuery};
+use dicom::core::Tag;
+use dicom::dictionary_std::tags;
+use pypx::{DicomHeader, PatientData, StudyDataMeta, StudyDataSeriesMeta};
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, Row};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{event, Level};
+
+/// Version of the schema, which is stored as the index's `user_version`.
+const SCHEMA_VERSION: i32 = 3;
+
+const SCHEMA: &str = "
+-- log/patientData/{PatientID}.json
+CREATE TABLE patient (
+    PatientID TEXT PRIMARY KEY,
+    PatientName TEXT NOT NULL,
+    PatientBirthDate TEXT NOT NULL,
+    PatientSex TEXT NOT NULL,
+    PatientAge TEXT NOT NULL
+);
+
+-- log/studyData/{StudyInstanceUID}-meta.json
+CREATE TABLE study (
+    StudyInstanceUID TEXT PRIMARY KEY,
+    PatientID TEXT NOT NULL,
+    StudyDescription TEXT NOT NULL,
+    StudyDate TEXT NOT NULL,
+    PerformedStationAETitle TEXT NOT NULL,
+    -- modification time of the file (nanoseconds since the epoch) when it was read,
+    -- or NULL if it must be read again
+    mtime INTEGER
+);
+CREATE INDEX study_order ON study (StudyDate DESC, StudyInstanceUID);
+
+-- log/studyData/{StudyInstanceUID}-series/{SeriesInstanceUID}-meta.json
+CREATE TABLE series (
+    SeriesInstanceUID TEXT PRIMARY KEY,
+    StudyInstanceUID TEXT NOT NULL REFERENCES study ON DELETE CASCADE,
+    -- attributes of the DICOM object of the file, NULL if it does not have them
+    Modality TEXT,
+    SeriesNumber TEXT,
+    SeriesDescription TEXT,
+    SeriesDate TEXT,
+    AccessionNumber TEXT,
+    StudyTime TEXT,
+    ReferringPhysicianName TEXT,
+    -- SeriesNumber, if it is a number
+    series_order INTEGER,
+    -- content of the file
+    meta TEXT NOT NULL
+);
+CREATE INDEX series_study ON series (StudyInstanceUID, series_order, SeriesInstanceUID);
+
+-- log/seriesData/{SeriesInstanceUID}-img/{InstanceNumber}-{SOPInstanceUID}.dcm.json
+CREATE TABLE instance (
+    SOPInstanceUID TEXT PRIMARY KEY,
+    SeriesInstanceUID TEXT NOT NULL REFERENCES series ON DELETE CASCADE,
+    InstanceNumber TEXT NOT NULL,
+    -- InstanceNumber, if it is a number
+    instance_order INTEGER
+);
+CREATE INDEX instance_series ON instance (SeriesInstanceUID, instance_order, SOPInstanceUID);
+";
+
+const INSERT_PATIENT: &str = "INSERT OR REPLACE INTO patient VALUES (?1, ?2, ?3, ?4, ?5)";
+
+const UPSERT_STUDY: &str = "
+INSERT INTO study VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+ON CONFLICT (StudyInstanceUID) DO UPDATE SET
+    PatientID = excluded.PatientID,
+    StudyDescription = excluded.StudyDescription,
+    StudyDate = excluded.StudyDate,
+    PerformedStationAETitle = excluded.PerformedStationAETitle,
+    mtime = excluded.mtime
+";
+
+// an upsert rather than INSERT OR REPLACE, which would delete the instances of the series
+const UPSERT_SERIES: &str = "
+INSERT INTO series VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+ON CONFLICT (SeriesInstanceUID) DO UPDATE SET
+    StudyInstanceUID = excluded.StudyInstanceUID,
+    Modality = excluded.Modality,
+    SeriesNumber = excluded.SeriesNumber,
+    SeriesDescription = excluded.SeriesDescription,
+    SeriesDate = excluded.SeriesDate,
+    AccessionNumber = excluded.AccessionNumber,
+    StudyTime = excluded.StudyTime,
+    ReferringPhysicianName = excluded.ReferringPhysicianName,
+    series_order = excluded.series_order,
+    meta = excluded.meta
+";
+
+const INSERT_INSTANCE: &str = "INSERT OR REPLACE INTO instance VALUES (?1, ?2, ?3, ?4)";
+
+/// Columns of a [Study] and its patient, see [study_of_row].
+const STUDY_COLUMNS: &str = "study.StudyInstanceUID, study.PatientID, study.StudyDescription, \
+    study.StudyDate, study.PerformedStationAETitle, patient.PatientName, \
+    patient.PatientBirthDate, patient.PatientSex, patient.PatientAge";
+
+/// How long to wait for another connection which is writing to the index.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Minimum time between two synchronizations of the index with the log directory.
+///
+/// Studies whose file was modified less than this long before a synchronization are
+/// read again by the next one, because `pypx` writes the files of their series and
+/// instances after it.
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A SQLite index of a `pypx` log directory, see [build_index].
+#[derive(Clone)]
+pub(crate) struct PypxIndex {
+    path: PathBuf,
+    log_dir: PathBuf,
+    /// When the index was last synchronized with the log directory.
+    last_sync: Arc<Mutex<Option<Instant>>>,
+}
+
+/// The level of the results of a query: each level includes the levels above it.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum QueryLevel {
+    Study,
+    Series,
+    Instance,
+}
+
+/// How an attribute is matched in SQL.
+enum Column {
+    /// A column which has a value for every row.
+    Value(&'static str),
+    /// A column which is `NULL` if the attribute is unknown, which matches anything.
+    Nullable(&'static str),
+    /// A column of the series of a study, which matches if any of its values matches
+    /// (e.g. `ModalitiesInStudy`).
+    AnySeries(&'static str),
+    /// The first non-empty value of a column of the series of a study, same as in
+    /// [StudySummary].
+    FirstSeries(&'static str),
+}
+
+/// A query, translated to SQL by [Selection::new].
+struct Selection {
+    level: QueryLevel,
+    sql: String,
+    params: Vec<SqlValue>,
+}
+
+/// A row selected by a [Selection]: a study, and the series and instance for the
+/// corresponding query levels.
+struct Selected {
+    study: Study,
+    /// `meta` of the series and its number of instances.
+    series: Option<(String, usize)>,
+    instance: Option<InstanceFile>,
+}
+
+impl PypxIndex {
+    /// Open an index of the log directory `log_dir` which was built by [build_index].
+    pub fn open(path: PathBuf, log_dir: PathBuf) -> Result<Self, IndexError> {
+        let conn = connect(&path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+        let version: i32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version != SCHEMA_VERSION {
+            return Err(IndexError::Version(path, version));
+        }
+        Ok(Self {
+            path,
+            log_dir,
+            last_sync: Default::default(),
+        })
+    }
+
+    /// Find the studies which match a query, with their patient and [StudySummary].
+    ///
+    /// Studies are sorted by `StudyDate` (most recent first) then by `StudyInstanceUID`.
+    pub async fn query_studies(
+        &self,
+        query: &QidoQuery,
+        pagination: Pagination,
+    ) -> Result<Page<Study>, IndexError> {
+        let selection = Selection::new(QueryLevel::Study, None, None, query);
+        self.query(move |conn| {
+            let studies = selection
+                .select(conn, pagination)?
+                .into_iter()
+                .map(|selected| {
+                    let mut study = selected.study;
+                    study.summary = Some(summarize(conn, &study.meta.StudyInstanceUID)?);
+                    Ok(study)
+                })
+                .collect::<Result<_, IndexError>>()?;
+            Ok(pagination.page_of(studies))
+        })
+        .await
+    }
+
+    /// Find the series which match a query, of a study or of every study, with their
+    /// study and number of instances.
+    ///
+    /// Series are sorted by study (see [PypxIndex::query_studies]), then by `SeriesNumber`
+    /// then by `SeriesInstanceUID`.
+    pub async fn query_series(
+        &self,
+        study_instance_uid: Option<&str>,
+        query: &QidoQuery,
+        pagination: Pagination,
+    ) -> Result<Page<(Study, StudyDataSeriesMeta<'static>, usize)>, IndexError> {
+        let selection = Selection::new(QueryLevel::Series, study_instance_uid, None, query);
+        self.query(move |conn| {
+            let series = selection
+                .select(conn, pagination)?
+                .into_iter()
+                .map(|selected| {
+                    let (meta, num_instances) = selected.series.unwrap_or_default();
+                    Ok((selected.study, deserialize(&meta)?, num_instances))
+                })
+                .collect::<Result<_, IndexError>>()?;
+            Ok(pagination.page_of(series))
+        })
+        .await
+    }
+
+    /// Find the instances which match a query, of a study and series or of all of them,
+    /// with their study and series (and its number of instances).
+    ///
+    /// Instances are sorted by series (see [PypxIndex::query_series]), then by
+    /// `InstanceNumber` then by `SOPInstanceUID`.
+    pub async fn query_instances(
+        &self,
+        study_instance_uid: Option<&str>,
+        series_instance_uid: Option<&str>,
+        query: &QidoQuery,
+        pagination: Pagination,
+    ) -> Result<Page<IndexedInstance>, IndexError> {
+        let selection = Selection::new(
+            QueryLevel::Instance,
+            study_instance_uid,
+            series_instance_uid,
+            query,
+        );
+        self.query(move |conn| {
+            let instances = selection
+                .select(conn, pagination)?
+                .into_iter()
+                .map(|selected| {
+                    let (meta, num_instances) = selected.series.unwrap_or_default();
+                    Ok(IndexedInstance {
+                        study: selected.study,
+                        series: deserialize(&meta)?,
+                        num_instances,
+                        instance: selected.instance.unwrap_or_default(),
+                    })
+                })
+                .collect::<Result<_, IndexError>>()?;
+            Ok(pagination.page_of(instances))
+        })
+        .await
+    }
+
+    /// Find the instances of a series which match a query, sorted by `InstanceNumber`
+    /// then by `SOPInstanceUID`. Also returns the total number of instances in the series.
+    pub async fn find_instances(
+        &self,
+        series_instance_uid: &str,
+        query: &QidoQuery,
+    ) -> Result<(Vec<InstanceFile>, usize), IndexError> {
+        let selection =
+            Selection::new(QueryLevel::Instance, None, Some(series_instance_uid), query);
+        let series_instance_uid = series_instance_uid.to_string();
+        self.query(move |conn| {
+            let instances = selection
+                .select(conn, Pagination::default())?
+                .into_iter()
+                .filter_map(|selected| selected.instance)
+                .collect();
+            Ok((instances, count_instances(conn, &series_instance_uid)?))
+        })
+        .await
+    }
+
+    /// Count the instances of a series.
+    pub async fn count_instances(&self, series_instance_uid: &str) -> Result<usize, IndexError> {
+        let series_instance_uid = series_instance_uid.to_string();
+        self.query(move |conn| count_instances(conn, &series_instance_uid))
+            .await
+    }
+
+    /// Add an instance, which was just written to the log directory by STOW-RS,
+    /// and its series, study and patient.
+    ///
+    /// The study is read again from the log directory by the next synchronization,
+    /// in case `pypx` wrote other instances of it at the same time.
+    pub async fn insert_instance(
+        &self,
+        header: &DicomHeader,
+        fs_location: &str,
+    ) -> Result<(), IndexError> {
+        let (header, fs_location) = (header.clone(), fs_location.to_string());
+        self.run(move |conn| {
+            let (series_base_dir, file_name) =
+                fs_location.rsplit_once('/').unwrap_or(("", &fs_location));
+            let instance = InstanceFile::from_path(Path::new(&format!("{file_name}.json")));
+            let study = StudyDataMeta::from_header(&header);
+            let series = StudyDataSeriesMeta::from_header(&header, series_base_dir);
+            let tx = conn.transaction()?;
+            if is_valid_patient_id(&study.PatientID) {
+                insert_patient(&tx, &PatientData::from_header(&header))?;
+            }
+            upsert_study(&tx, &study.StudyInstanceUID, &study, None)?;
+            upsert_series(&tx, &study.StudyInstanceUID, &series)?;
+            if let Some(instance) = instance {
+                insert_instance(&tx, &series.SeriesInstanceUID, &instance)?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Run a query on the index, after synchronizing it with the log directory if it was
+    /// not synchronized in the last [SYNC_INTERVAL].
+    async fn query<T, F>(&self, f: F) -> Result<T, IndexError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, IndexError> + Send + 'static,
+    {
+        let log_dir = self.log_dir.to_path_buf();
+        let last_sync = Arc::clone(&self.last_sync);
+        self.run(move |conn| {
+            // concurrent queries wait for the synchronization
+            let mut last_sync = last_sync.lock().unwrap_or_else(|e| e.into_inner());
+            if last_sync.map_or(true, |t| t.elapsed() >= SYNC_INTERVAL) {
+                let count = sync(conn, &log_dir, SystemTime::now() - SYNC_INTERVAL)?;
+                if count > 0 {
+                    event!(Level::INFO, "Read {count} studies into the index");
+                }
+                *last_sync = Some(Instant::now());
+            }
+            drop(last_sync);
+            f(conn)
+        })
+        .await
+    }
+
+    /// Run a function on a new connection in a blocking thread.
+    async fn run<T, F>(&self, f: F) -> Result<T, IndexError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, IndexError> + Send + 'static,
+    {
+        let path = self.path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            f(&mut connect(&path, OpenFlags::SQLITE_OPEN_READ_WRITE)?)
+        })
+        .await
+        .map_err(|error| IndexError::Runtime(error.into()))?
+    }
+}
+
+/// An instance found by [PypxIndex::query_instances].
+pub(crate) struct IndexedInstance {
+    pub study: Study,
+    pub series: StudyDataSeriesMeta<'static>,
+    /// Number of instances of the series.
+    pub num_instances: usize,
+    pub instance: InstanceFile,
+}
+
+impl Selection {
+    /// Translate a query at the given level to SQL, for the study and series with the
+    /// given UIDs if any.
+    ///
+    /// Matching keys are applied to the first level (from the study) which has their
+    /// attribute. Keys for other attributes are ignored, same as [QidoQuery::matches]
+    /// ignores keys for attributes which are unknown.
+    fn new(
+        level: QueryLevel,
+        study_instance_uid: Option<&str>,
+        series_instance_uid: Option<&str>,
+        query: &QidoQuery,
+    ) -> Self {
+        let mut columns = STUDY_COLUMNS.to_string();
+        let mut from = "study LEFT JOIN patient ON patient.PatientID = study.PatientID".to_string();
+        let mut order = "study.StudyDate DESC, study.StudyInstanceUID".to_string();
+        let mut column_of: Vec<fn(Tag) -> Option<Column>> = vec![study_column];
+        if level >= QueryLevel::Series {
+            columns += ", series.meta, (SELECT COUNT(*) FROM instance AS i \
+                WHERE i.SeriesInstanceUID = series.SeriesInstanceUID)";
+            from += " JOIN series ON series.StudyInstanceUID = study.StudyInstanceUID";
+            order += ", series.series_order, series.SeriesInstanceUID";
+            column_of.push(series_column);
+        }
+        if level >= QueryLevel::Instance {
+            columns += ", instance.SOPInstanceUID, instance.InstanceNumber";
+            from += " JOIN instance ON instance.SeriesInstanceUID = series.SeriesInstanceUID";
+            order += ", instance.instance_order, instance.SOPInstanceUID";
+            column_of.push(instance_column);
+        }
+
+        let mut conditions = Vec::new();
+        let mut params = Vec::new();
+        if let Some(uid) = study_instance_uid {
+            conditions.push("study.StudyInstanceUID = ?".to_string());
+            params.push(uid.to_string());
+        }
+        if let Some(uid) = series_instance_uid {
+            conditions.push("series.SeriesInstanceUID = ?".to_string());
+            params.push(uid.to_string());
+        }
+        for key in query.keys() {
+            let Some(column) = column_of.iter().find_map(|column_of| column_of(key.tag())) else {
+                continue;
+            };
+            let condition = match column {
+                Column::Value(column) => key.to_sql(column),
+                Column::Nullable(column) => key
+                    .to_sql(column)
+                    .map(|(condition, p)| (format!("({column} IS NULL OR {condition})"), p)),
+                Column::AnySeries(column) => key.to_sql(&format!("s.{column}")).map(|(c, p)| {
+                    let condition = format!(
+                        "EXISTS (SELECT 1 FROM series AS s \
+                        WHERE s.StudyInstanceUID = study.StudyInstanceUID AND {c})"
+                    );
+                    (condition, p)
+                }),
+                Column::FirstSeries(column) => key.to_sql(&format!(
+                    "coalesce((SELECT trim(s.{column}) FROM series AS s \
+                    WHERE s.StudyInstanceUID = study.StudyInstanceUID AND trim(s.{column}) <> '' \
+                    ORDER BY s.series_order, s.SeriesInstanceUID LIMIT 1), '')"
+                )),
+            };
+            if let Some((condition, p)) = condition {
+                conditions.push(condition);
+                params.extend(p);
+            }
+        }
+        let conditions = if conditions.is_empty() {
+            "1".to_string()
+        } else {
+            conditions.join(" AND ")
+        };
+        Self {
+            level,
+            sql: format!(
+                "SELECT {columns} FROM {from} WHERE {conditions} ORDER BY {order} LIMIT ? OFFSET ?"
+            ),
+            params: params.into_iter().map(SqlValue::Text).collect(),
+        }
+    }
+
+    /// Select the rows of a page, plus the next row if any, see [Pagination::page_of].
+    fn select(
+        self,
+        conn: &Connection,
+        pagination: Pagination,
+    ) -> Result<Vec<Selected>, IndexError> {
+        let params = self.params.into_iter().chain([
+            SqlValue::Integer(pagination.sql_limit()),
+            SqlValue::Integer(pagination.sql_offset()),
+        ]);
+        let mut stmt = conn.prepare(&self.sql)?;
+        let rows = stmt.query_map(params_from_iter(params), |row| {
+            let series = if self.level >= QueryLevel::Series {
+                Some((row.get(9)?, row.get(10)?))
+            } else {
+                None
+            };
+            let instance = if self.level >= QueryLevel::Instance {
+                Some(InstanceFile {
+                    sop_instance_uid: row.get(11)?,
+                    instance_number: row.get(12)?,
+                })
+            } else {
+                None
+            };
+            Ok(Selected {
+                study: study_of_row(row)?,
+                series,
+                instance,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+
+/// Columns of the attributes of a study (or of its patient, or aggregated from its series)
+/// which can be matched.
+fn study_column(tag: Tag) -> Option<Column> {
+    let column = match tag {
+        tags::STUDY_INSTANCE_UID => Column::Value("study.StudyInstanceUID"),
+        tags::PATIENT_ID => Column::Value("study.PatientID"),
+        tags::STUDY_DESCRIPTION => Column::Value("study.StudyDescription"),
+        tags::STUDY_DATE => Column::Value("study.StudyDate"),
+        tags::PERFORMED_STATION_AE_TITLE => Column::Value("study.PerformedStationAETitle"),
+        // NULL if the study has no patientData
+        tags::PATIENT_NAME => Column::Nullable("patient.PatientName"),
+        tags::PATIENT_BIRTH_DATE => Column::Nullable("patient.PatientBirthDate"),
+        tags::PATIENT_SEX => Column::Nullable("patient.PatientSex"),
+        tags::PATIENT_AGE => Column::Nullable("patient.PatientAge"),
+        tags::MODALITIES_IN_STUDY => Column::AnySeries("Modality"),
+        tags::ACCESSION_NUMBER => Column::FirstSeries("AccessionNumber"),
+        tags::STUDY_TIME => Column::FirstSeries("StudyTime"),
+        tags::REFERRING_PHYSICIAN_NAME => Column::FirstSeries("ReferringPhysicianName"),
+        _ => return None,
+    };
+    Some(column)
+}
+
+/// Columns of the attributes of a series which can be matched.
+fn series_column(tag: Tag) -> Option<Column> {
+    let column = match tag {
+        tags::SERIES_INSTANCE_UID => Column::Value("series.SeriesInstanceUID"),
+        tags::MODALITY => Column::Nullable("series.Modality"),
+        tags::SERIES_NUMBER => Column::Nullable("series.SeriesNumber"),
+        tags::SERIES_DESCRIPTION => Column::Nullable("series.SeriesDescription"),
+        tags::SERIES_DATE => Column::Nullable("series.SeriesDate"),
+        _ => return None,
+    };
+    Some(column)
+}
+
+/// Columns of the attributes of an instance which can be matched.
+fn instance_column(tag: Tag) -> Option<Column> {
+    let column = match tag {
+        tags::SOP_INSTANCE_UID => Column::Value("instance.SOPInstanceUID"),
+        tags::INSTANCE_NUMBER => Column::Value("instance.InstanceNumber"),
+        _ => return None,
+    };
+    Some(column)
+}
+
+/// Get a study and its patient from the [STUDY_COLUMNS] of a row.
+fn study_of_row(row: &Row) -> rusqlite::Result<Study> {
+    let meta = StudyDataMeta {
+        StudyInstanceUID: row.get::<_, String>(0)?.into(),
+        PatientID: row.get::<_, String>(1)?.into(),
+        StudyDescription: row.get::<_, String>(2)?.into(),
+        StudyDate: row.get::<_, String>(3)?.into(),
+        PerformedStationAETitle: row.get::<_, String>(4)?.into(),
+    };
+    let patient = match row.get::<_, Option<String>>(5)? {
+        Some(patient_name) => Some(Arc::new(PatientData {
+            PatientID: meta.PatientID.clone(),
+            PatientName: patient_name.into(),
+            PatientBirthDate: row.get::<_, String>(6)?.into(),
+            PatientSex: row.get::<_, String>(7)?.into(),
+            PatientAge: row.get::<_, String>(8)?.into(),
+            StudyList: Vec::new(),
+        })),
+        None => None,
+    };
+    Ok(Study {
+        meta,
+        patient,
+        summary: None,
+    })
+}
+
+/// Aggregate the attributes of the series of a study, and count its instances.
+fn summarize(conn: &Connection, study_instance_uid: &str) -> Result<StudySummary, IndexError> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT meta FROM series WHERE StudyInstanceUID = ?1 \
+        ORDER BY series_order, SeriesInstanceUID",
+    )?;
+    let series = stmt
+        .query_map([study_instance_uid], |row| row.get::<_, String>(0))?
+        .map(|meta| deserialize(&meta?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let num_instances = conn.query_row(
+        "SELECT COUNT(*) FROM instance JOIN series USING (SeriesInstanceUID) \
+        WHERE series.StudyInstanceUID = ?1",
+        [study_instance_uid],
+        |row| row.get(0),
+    )?;
+    Ok(StudySummary::new(&series, num_instances))
+}
+
+fn count_instances(conn: &Connection, series_instance_uid: &str) -> Result<usize, IndexError> {
+    let count = conn.query_row(
+        "SELECT COUNT(*) FROM instance WHERE SeriesInstanceUID = ?1",
+        [series_instance_uid],
+        |row| row.get(0),
+    )?;
+    Ok(count)
+}
+
+/// Build an index of the `pypx` log directory `log_dir`, replacing the index at
+/// `path` (if any) once it is complete. Files which cannot be read are skipped.
+///
+/// Returns the number of studies in the index.
+pub(crate) fn build_index(log_dir: &Path, path: &Path) -> Result<usize, IndexError> {
+    let tmp = path.with_extension("tmp");
+    if tmp.exists() {
+        std::fs::remove_file(&tmp).map_err(|e| IndexError::IO(tmp.to_path_buf(), e.kind()))?;
+    }
+    let conn = connect(
+        &tmp,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+    )?;
+    // readers are not blocked while the index is synchronized
+    conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+    conn.execute_batch(SCHEMA)?;
+    conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    let count = sync(&conn, log_dir, SystemTime::now() - SYNC_INTERVAL)?;
+    conn.close().map_err(|(_, error)| error)?;
+    std::fs::rename(&tmp, path).map_err(|e| IndexError::IO(path.to_path_buf(), e.kind()))?;
+    Ok(count)
+}
+
+/// Synchronize the index with the log directory `log_dir`: read the studies whose
+/// `studyData/{StudyInstanceUID}-meta.json` file is new or was modified (or whose
+/// modification time was not recorded), and remove the studies whose file was removed.
+/// Modification times are only recorded for files which were modified before
+/// `settled_before`. Files which cannot be read are skipped.
+///
+/// Returns the number of studies which were read.
+fn sync(
+    conn: &Connection,
+    log_dir: &Path,
+    settled_before: SystemTime,
+) -> Result<usize, IndexError> {
+    let tx = conn.unchecked_transaction()?;
+    let mut indexed: HashMap<String, Option<i64>> = tx
+        .prepare("SELECT StudyInstanceUID, mtime FROM study")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    let mut count = 0;
+    for (study_instance_uid, path, modified) in ls_studies(&log_dir.join("studyData"))? {
+        let mtime = modified
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .and_then(|d| i64::try_from(d.as_nanos()).ok());
+        let indexed_mtime = indexed.remove(&study_instance_uid).flatten();
+        if indexed_mtime.is_some() && indexed_mtime == mtime {
+            continue;
+        }
+        tx.execute(
+            "DELETE FROM study WHERE StudyInstanceUID = ?1",
+            [&study_instance_uid],
+        )?;
+        let study = match read_study_meta(&path) {
+            Ok(study) => study,
+            Err(e) => {
+                event!(Level::WARN, "{:?}", e);
+                continue;
+            }
+        };
+        let mtime = mtime.filter(|_| modified < settled_before);
+        insert_study(&tx, log_dir, &study_instance_uid, &study, mtime)?;
+        count += 1;
+    }
+    for study_instance_uid in indexed.keys() {
+        tx.execute(
+            "DELETE FROM study WHERE StudyInstanceUID = ?1",
+            [study_instance_uid],
+        )?;
+    }
+    tx.commit()?;
+    Ok(count)
+}
+
+/// Add a study, its patient, series and instances to the index.
+fn insert_study(
+    conn: &Connection,
+    log_dir: &Path,
+    study_instance_uid: &str,
+    study: &StudyDataMeta,
+    mtime: Option<i64>,
+) -> Result<(), IndexError> {
+    upsert_study(conn, study_instance_uid, study, mtime)?;
+    if is_valid_patient_id(&study.PatientID) {
+        let path = log_dir
+            .join("patientData")
+            .join(format!("{}.json", study.PatientID));
+        match read_1member_json(&path) {
+            Ok(patient) => insert_patient(conn, &patient)?,
+            Err(FileError::NotFound(_)) => (),
+            Err(e) => event!(Level::WARN, "{:?}", e),
+        }
+    }
+    let series_dir = log_dir
+        .join("studyData")
+        .join(format!("{study_instance_uid}-series"));
+    for series_file in ls_if_exists(&series_dir, "-meta.json")? {
+        let series: StudyDataSeriesMeta = match read_1member_json(&series_file) {
+            Ok(series) => series,
+            Err(e) => {
+                event!(Level::WARN, "{:?}", e);
+                continue;
+            }
+        };
+        upsert_series(conn, study_instance_uid, &series)?;
+        let instances_dir = log_dir
+            .join("seriesData")
+            .join(format!("{}-img", series.SeriesInstanceUID));
+        for instance_file in ls_if_exists(&instances_dir, ".dcm.json")? {
+            if let Some(instance) = InstanceFile::from_path(&instance_file) {
+                insert_instance(conn, &series.SeriesInstanceUID, &instance)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn insert_patient(conn: &Connection, patient: &PatientData) -> Result<(), IndexError> {
+    conn.prepare_cached(INSERT_PATIENT)?.execute([
+        &patient.PatientID,
+        &patient.PatientName,
+        &patient.PatientBirthDate,
+        &patient.PatientSex,
+        &patient.PatientAge,
+    ])?;
+    Ok(())
+}
+
+fn upsert_study(
+    conn: &Connection,
+    study_instance_uid: &str,
+    study: &StudyDataMeta,
+    mtime: Option<i64>,
+) -> Result<(), IndexError> {
+    conn.prepare_cached(UPSERT_STUDY)?.execute(params![
+        study_instance_uid,
+        study.PatientID,
+        study.StudyDescription,
+        study.StudyDate,
+        study.PerformedStationAETitle,
+        mtime,
+    ])?;
+    Ok(())
+}
+
+fn upsert_series(
+    conn: &Connection,
+    study_instance_uid: &str,
+    series: &StudyDataSeriesMeta,
+) -> Result<(), IndexError> {
+    let value_of = |keyword: &str| series.DICOM.get(keyword).map(|v| v.value.as_ref());
+    let series_order = value_of("SeriesNumber").and_then(|v| v.trim().parse::<i64>().ok());
+    conn.prepare_cached(UPSERT_SERIES)?.execute(params![
+        series.SeriesInstanceUID,
+        study_instance_uid,
+        value_of("Modality"),
+        value_of("SeriesNumber"),
+        value_of("SeriesDescription"),
+        value_of("SeriesDate"),
+        value_of("AccessionNumber"),
+        value_of("StudyTime"),
+        value_of("ReferringPhysicianName"),
+        series_order,
+        serialize(series),
+    ])?;
+    Ok(())
+}
+
+fn insert_instance(
+    conn: &Connection,
+    series_instance_uid: &str,
+    instance: &InstanceFile,
+) -> Result<(), IndexError> {
+    conn.prepare_cached(INSERT_INSTANCE)?.execute(params![
+        instance.sop_instance_uid,
+        series_instance_uid,
+        instance.instance_number,
+        instance.instance_number.parse::<i64>().ok(),
+    ])?;
+    Ok(())
+}
+
+/// Same as `PypxReader::get_patient`, a `PatientID` which can be a file name.
+fn is_valid_patient_id(patient_id: &str) -> bool {
+    !(patient_id.is_empty() || patient_id.starts_with('.') || patient_id.contains('/'))
+}
+
+/// List the `{StudyInstanceUID}-meta.json` files of `log/studyData`, with their
+/// `StudyInstanceUID` and modification time.
+fn ls_studies(study_data_dir: &Path) -> Result<Vec<(String, PathBuf, SystemTime)>, IndexError> {
+    let files = ls(study_data_dir, "-meta.json")?
+        .into_iter()
+        .filter_map(|path| {
+            let study_instance_uid = path
+                .file_name()?
+                .to_str()?
+                .strip_suffix("-meta.json")?
+                .to_string();
+            let modified = path
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .map_err(|e| event!(Level::WARN, "Cannot stat {:?}: {:?}", path, e))
+                .ok()?;
+            Some((study_instance_uid, path, modified))
+        })
+        .collect();
+    Ok(files)
+}
+
+/// List the files of a directory which have a given suffix.
+fn ls(dir: &Path, suffix: &str) -> Result<Vec<PathBuf>, IndexError> {
+    let read_dir =
+        std::fs::read_dir(dir).map_err(|e| IndexError::IO(dir.to_path_buf(), e.kind()))?;
+    let files = read_dir
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.ends_with(suffix))
+        })
+        .collect();
+    Ok(files)
+}
+
+/// Same as [ls], but a directory which does not exist (yet) is empty.
+fn ls_if_exists(dir: &Path, suffix: &str) -> Result<Vec<PathBuf>, IndexError> {
+    match ls(dir, suffix) {
+        Err(IndexError::IO(_, std::io::ErrorKind::NotFound)) => Ok(Vec::new()),
+        result => result,
+    }
+}
+
+/// Read a `log/studyData/{StudyInstanceUID}-meta.json` file, which might be affected by
+/// a bug in `rx-repack` (see `read_study_meta_json` in `pypx_reader.rs`).
+fn read_study_meta(path: &Path) -> Result<StudyDataMeta<'static>, FileError> {
+    read_1member_json(path).or_else(|error| match error {
+        FileError::Malformed(..) => read_json(path),
+        _ => Err(error),
+    })
+}
+
+/// Blocking equivalent of [crate::json_files::read_1member_json_file].
+fn read_1member_json<T: DeserializeOwned>(path: &Path) -> Result<T, FileError> {
+    let data: HashMap<String, T> = read_json(path)?;
+    data.into_values()
+        .next()
+        .ok_or_else(|| FileError::Malformed(path.to_path_buf(), "Empty object".to_string(), None))
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, FileError> {
+    let data = std::fs::read(path).map_err(|e| FileError::from_io_error(path.to_path_buf(), e))?;
+    serde_json::from_slice(&data).map_err(|error| {
+        FileError::Malformed(
+            path.to_path_buf(),
+            "Could not deserialize".to_string(),
+            Some(error.into()),
+        )
+    })
+}
+
+fn connect(path: &Path, flags: OpenFlags) -> Result<Connection, IndexError> {
+    let conn = Connection::open_with_flags(path, flags | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.pragma_update(None, "foreign_keys", true)?;
+    Ok(conn)
+}
+
+fn serialize<T: serde::Serialize>(value: &T) -> String {
+    // serializing our own structs as JSON cannot fail
+    serde_json::to_string(value).unwrap()
+}
+
+fn deserialize<T: DeserializeOwned>(meta: &str) -> Result<T, IndexError> {
+    serde_json::from_str(meta).map_err(IndexError::Malformed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pypx_reader::PypxReader;
+    use crate::qido::{
+        IncludeField, INSTANCE_MATCHING_TAGS, SERIES_MATCHING_TAGS, STUDY_MATCHING_TAGS,
+    };
+    use pypx::PypxLogWriter;
+    use rstest::*;
+    use serde_json::Value;
+    use tempfile::TempDir;
+
+    /// `StudyInstanceUID`, `SeriesInstanceUID`, `SOPInstanceUID` and other attributes
+    /// of an instance.
+    type TestInstance = (
+        &'static str,
+        &'static str,
+        &'static str,
+        &'static [(&'static str, &'static str)],
+    );
+
+    /// Instances of the log directory of [log_dir].
+    const INSTANCES: [TestInstance; 6] = [
+        (
+            "1.1",
+            "1.1.1",
+            "1.1.1.1",
+            &[
+                ("SeriesNumber", "2"),
+                ("Modality", "CT"),
+                ("InstanceNumber", "2"),
+            ],
+        ),
+        (
+            "1.1",
+            "1.1.1",
+            "1.1.1.2",
+            &[
+                ("SeriesNumber", "2"),
+                ("Modality", "CT"),
+                ("InstanceNumber", "10"),
+            ],
+        ),
+        (
+            "1.1",
+            "1.1.1",
+            "1.1.1.3",
+            &[("SeriesNumber", "2"), ("Modality", "CT")],
+        ),
+        (
+            "1.1",
+            "1.1.2",
+            "1.1.2.1",
+            &[
+                ("SeriesNumber", "1"),
+                ("Modality", "MR"),
+                ("AccessionNumber", "A1"),
+                ("SeriesDescription", "Brain"),
+            ],
+        ),
+        (
+            "2.2",
+            "2.2.1",
+            "2.2.1.1",
+            &[("Modality", "US"), ("InstanceNumber", "1")],
+        ),
+        (
+            "3.3",
+            "3.3.1",
+            "3.3.1.1",
+            &[("Modality", "CT"), ("InstanceNumber", "1")],
+        ),
+    ];
+
+    /// Attributes of the studies of [INSTANCES].
+    fn study_attributes(study_instance_uid: &str) -> &'static [(&'static str, &'static str)] {
+        match study_instance_uid {
+            "1.1" => &[
+                ("PatientID", "P1"),
+                ("PatientName", "Doe^John"),
+                ("PatientSex", "M"),
+                ("StudyDate", "20230101"),
+            ],
+            "2.2" => &[
+                ("PatientID", "P2"),
+                ("PatientName", "Roe^Jane"),
+                ("PatientSex", "F"),
+                ("StudyDate", "20220101"),
+            ],
+            // no patientData
+            _ => &[("StudyDate", "20220101")],
+        }
+    }
+
+    /// Write the files of an instance to a log directory, same as STOW-RS.
+    /// Returns its header and `FSlocation`.
+    fn write_instance(
+        log_dir: &Path,
+        (study, series, instance, attributes): TestInstance,
+    ) -> (DicomHeader, String) {
+        let header: DicomHeader = [
+            ("StudyInstanceUID", study),
+            ("SeriesInstanceUID", series),
+            ("SOPInstanceUID", instance),
+        ]
+        .iter()
+        .chain(study_attributes(study))
+        .chain(attributes)
+        .map(|(keyword, value)| (keyword.to_string(), value.to_string()))
+        .collect();
+        let instance_number = header.get("InstanceNumber").map_or("0000", |n| n.as_str());
+        let fs_location = format!("/data/{series}/{instance_number}-{instance}.dcm");
+        PypxLogWriter::new(log_dir)
+            .write(&header, &fs_location)
+            .unwrap();
+        (header, fs_location)
+    }
+
+    #[fixture]
+    fn log_dir() -> TempDir {
+        let tmp = tempfile::tempdir().unwrap();
+        let log_dir = tmp.path().join("log");
+        for instance in INSTANCES {
+            write_instance(&log_dir, instance);
+        }
+        tmp
+    }
+
+    fn open_index(tmp: &TempDir) -> PypxIndex {
+        let (log_dir, path) = (tmp.path().join("log"), tmp.path().join("index.sqlite"));
+        build_index(&log_dir, &path).unwrap();
+        PypxIndex::open(path, log_dir).unwrap()
+    }
+
+    fn reader(tmp: &TempDir, index: Option<PypxIndex>) -> PypxReader {
+        let data_dir = tmp.path().join("data");
+        PypxReader::new(
+            &tmp.path().join("log"),
+            data_dir.clone(),
+            data_dir,
+            0,
+            tmp.path().join("thumbnails"),
+            index,
+        )
+        .unwrap()
+    }
+
+    /// Synchronize the index with its log directory, recording the modification time of
+    /// every file. Also prevents the index from synchronizing itself.
+    fn sync_now(index: &PypxIndex) -> usize {
+        let conn = connect(&index.path, OpenFlags::SQLITE_OPEN_READ_WRITE).unwrap();
+        let settled_before = SystemTime::now() + Duration::from_secs(3600);
+        let count = sync(&conn, &index.log_dir, settled_before).unwrap();
+        *index.last_sync.lock().unwrap() = Some(Instant::now());
+        count
+    }
+
+    async fn study_instance_uids(index: &PypxIndex, query: &QidoQuery) -> Vec<String> {
+        let page = index
+            .query_studies(query, Pagination::default())
+            .await
+            .unwrap();
+        page.items
+            .into_iter()
+            .map(|study| study.meta.StudyInstanceUID.to_string())
+            .collect()
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_build_index(log_dir: TempDir) {
+        std::fs::write(log_dir.path().join("log/studyData/4.4-meta.json"), "{").unwrap();
+        let path = log_dir.path().join("index.sqlite");
+        assert_eq!(build_index(&log_dir.path().join("log"), &path).unwrap(), 3);
+
+        let index = PypxIndex::open(path, log_dir.path().join("log")).unwrap();
+        let page = index
+            .query_studies(&QidoQuery::default(), Pagination::default())
+            .await
+            .unwrap();
+        assert!(!page.truncated);
+        let study = &page.items[0];
+        assert_eq!(study.meta.StudyInstanceUID, "1.1");
+        assert_eq!(study.patient.as_ref().unwrap().PatientName, "Doe^John");
+        let summary = study.summary.as_ref().unwrap();
+        assert_eq!(summary.modalities, "CT\\MR");
+        assert_eq!((summary.num_series, summary.num_instances), (2, 4));
+        assert!(page.items[2].patient.is_none());
+        assert_eq!(index.count_instances("1.1.1").await.unwrap(), 3);
+    }
+
+    #[rstest]
+    #[case(&[])]
+    #[case(&[("PatientID", "P1")])]
+    #[case(&[("PatientName", "doe*")])]
+    #[case(&[("PatientName", "ROE^JANE")])]
+    #[case(&[("PatientSex", "F")])]
+    #[case(&[("StudyDate", "20220101-20221231")])]
+    #[case(&[("StudyDate", "2023.01.01-")])]
+    #[case(&[("StudyInstanceUID", "1.1\\3.3")])]
+    #[case(&[("ModalitiesInStudy", "MR")])]
+    #[case(&[("ModalitiesInStudy", "US,MR")])]
+    #[case(&[("AccessionNumber", "A1")])]
+    #[case(&[("AccessionNumber", "A?")])]
+    #[case(&[("Modality", "CT")])]
+    #[case(&[("SeriesNumber", "2")])]
+    #[case(&[("SeriesDescription", "brain"), ("fuzzymatching", "true")])]
+    #[case(&[("SeriesInstanceUID", "1.1.2")])]
+    #[case(&[("InstanceNumber", "10")])]
+    #[case(&[("SOPInstanceUID", "1.1.1.1,3.3.1.1")])]
+    #[case(&[("Modality", "CT"), ("limit", "1")])]
+    #[case(&[("limit", "2"), ("offset", "1")])]
+    #[case(&[("offset", "10")])]
+    #[tokio::test]
+    async fn test_query_same_as_files(log_dir: TempDir, #[case] params: &[(&str, &str)]) {
+        let params = params
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let query = QidoQuery::parse(&params).unwrap();
+        let pagination = Pagination::parse(&params).unwrap();
+        let files = reader(&log_dir, None);
+        let indexed = reader(&log_dir, Some(open_index(&log_dir)));
+        assert_eq!(
+            query_every_level(&indexed, &query, pagination).await,
+            query_every_level(&files, &query, pagination).await
+        );
+    }
+
+    /// Query every level of [INSTANCES] which supports the keys of `query`, same as
+    /// the router.
+    async fn query_every_level(
+        pypx: &PypxReader,
+        query: &QidoQuery,
+        pagination: Pagination,
+    ) -> Vec<(Vec<Value>, bool)> {
+        let include = &IncludeField::None;
+        let study = STUDY_MATCHING_TAGS.as_slice();
+        let series = SERIES_MATCHING_TAGS.as_slice();
+        let instance = INSTANCE_MATCHING_TAGS.as_slice();
+        let supports = |tags: &[&[Tag]]| query.check_supported(&tags.concat()).is_ok();
+        let mut pages = Vec::new();
+        if supports(&[study]) {
+            pages.push(
+                pypx.query_studies(query, pagination, include)
+                    .await
+                    .unwrap(),
+            );
+        }
+        if supports(&[series]) {
+            let page = pypx.query_series("1.1", query, pagination, include);
+            pages.push(page.await.unwrap());
+        }
+        if supports(&[study, series]) {
+            let page = pypx.query_all_series(query, pagination, include);
+            pages.push(page.await.unwrap());
+        }
+        if supports(&[series, instance]) {
+            let page = pypx.query_instances("1.1", "1.1.1", query, pagination, include, "");
+            pages.push(page.await.unwrap());
+        }
+        if supports(&[study, series, instance]) {
+            let page = pypx.query_all_instances(query, pagination, include, "");
+            pages.push(page.await.unwrap());
+        }
+        pages
+            .into_iter()
+            .map(|page| (page.items, page.truncated))
+            .collect()
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_sync(log_dir: TempDir) {
+        let index = open_index(&log_dir);
+        let log = log_dir.path().join("log");
+        // files which were just modified are read again by every synchronization
+        assert_eq!(sync_now(&index), 3);
+        assert_eq!(sync_now(&index), 0);
+
+        // studies are not read again unless their studyData file is modified
+        std::fs::remove_file(log.join("seriesData/1.1.1-img/0000-1.1.1.3.dcm.json")).unwrap();
+        assert_eq!(sync_now(&index), 0);
+        assert_eq!(index.count_instances("1.1.1").await.unwrap(), 3);
+
+        // wait for the modification time to change
+        std::thread::sleep(Duration::from_millis(50));
+        write_instance(&log, ("1.1", "1.1.3", "1.1.3.1", &[("Modality", "SR")]));
+        std::fs::remove_file(log.join("studyData/2.2-meta.json")).unwrap();
+        assert_eq!(sync_now(&index), 1);
+        assert_eq!(index.count_instances("1.1.1").await.unwrap(), 2);
+        assert_eq!(index.count_instances("1.1.3").await.unwrap(), 1);
+        let query = QidoQuery::default();
+        assert_eq!(study_instance_uids(&index, &query).await, ["1.1", "3.3"]);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_insert_instance(log_dir: TempDir) {
+        let index = open_index(&log_dir);
+        sync_now(&index);
+        let log = log_dir.path().join("log");
+        let instance = ("4.4", "4.4.1", "4.4.1.1", &[("InstanceNumber", "7")][..]);
+        let (header, fs_location) = write_instance(&log, instance);
+        index.insert_instance(&header, &fs_location).await.unwrap();
+
+        let page = index
+            .query_instances(
+                Some("4.4"),
+                None,
+                &QidoQuery::default(),
+                Pagination::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0].instance.instance_number, "7");
+        assert_eq!(page.items[0].series.SeriesBaseDir, "/data/4.4.1");
+
+        // the study is read again from its files by the next synchronization
+        assert_eq!(sync_now(&index), 1);
+        assert_eq!(index.count_instances("4.4.1").await.unwrap(), 1);
+    }
+}
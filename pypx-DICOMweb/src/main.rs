@@ -1,14 +1,29 @@
+mod archive;
 mod constants;
 mod dicom;
 mod errors;
+mod frames;
+mod index;
 mod json_files;
+mod multipart;
 mod pypx_reader;
+mod pypx_writer;
+mod qido;
+mod rendered;
 mod router;
+mod stow;
+mod thumbnail;
 mod translate;
 
+use crate::index::{build_index, PypxIndex};
 use crate::pypx_reader::PypxReader;
+use crate::pypx_writer::PypxWriter;
 use crate::router::get_router;
-use axum::{http::Method, routing::get, Router};
+use axum::{
+    http::{header, Method},
+    routing::get,
+    Router,
+};
 use std::path::PathBuf;
 use tower_http::cors::{Any, CorsLayer};
 use axum_prometheus::PrometheusMetricLayerBuilder;
@@ -17,24 +32,62 @@ use axum_prometheus::PrometheusMetricLayerBuilder;
 async fn main() {
     init_logging();
 
+    let log_dir = get_path_env("PYPX_LOG_DIR");
+    let index_path = std::env::var("PYPX_INDEX").ok().map(PathBuf::from);
+    if std::env::args().nth(1).as_deref() == Some("index") {
+        let Some(index_path) = index_path else {
+            eprintln!("PYPX_INDEX must be set to build an index");
+            std::process::exit(1);
+        };
+        match build_index(&log_dir, &index_path) {
+            Ok(count) => println!("Indexed {count} studies to {index_path:?}"),
+            Err(e) => {
+                eprintln!("Failed to build the index {index_path:?}: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let index = index_path.and_then(|path| {
+        PypxIndex::open(path, log_dir.clone())
+            .map_err(|e| tracing::event!(tracing::Level::WARN, "Not using index: {}", e))
+            .ok()
+    });
+
     let port = get_port();
+    let data_dir = get_path_env("PYPX_DATA_DIR");
+    let repack_data_dir_mountpath = get_path_env("PYPX_REPACK_DATA_MOUNTPOINT");
+    let thumbnail_dir = std::env::var("PYPX_THUMBNAIL_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| data_dir.with_file_name("thumbnails"));
+    let writer = PypxWriter::new(
+        &log_dir,
+        data_dir.clone(),
+        repack_data_dir_mountpath.clone(),
+        index.clone(),
+    );
     let pypx = PypxReader::new(
-        &get_path_env("PYPX_LOG_DIR"),
-        get_path_env("PYPX_DATA_DIR"),
-        get_path_env("PYPX_REPACK_DATA_MOUNTPOINT"),
+        &log_dir,
+        data_dir,
+        repack_data_dir_mountpath,
+        get_bulkdata_threshold(),
+        thumbnail_dir,
+        index,
     )
     .unwrap();
 
     let cors = CorsLayer::new()
-        .allow_methods([Method::GET])
-        .allow_origin(Any);
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([header::CONTENT_TYPE, header::ACCEPT])
+        .allow_origin(Any)
+        .expose_headers([header::WARNING]);
 
     let (prometheus_layer, metric_handle) = PrometheusMetricLayerBuilder::new()
         .with_prefix("pypx_dicomweb_axum")
         .with_ignore_pattern("/metrics")
         .with_default_metrics()
         .build_pair();
-    let pypx_dicomweb_router = get_router(pypx)
+    let pypx_dicomweb_router = get_router(pypx, writer)
         .layer(prometheus_layer);
 
     let app = Router::new()
@@ -77,6 +130,13 @@ fn get_port() -> u32 {
         .unwrap_or_else(|_| panic!("Failed to parse PORT={s} as an integer"))
 }
 
+fn get_bulkdata_threshold() -> usize {
+    let s = std::env::var("PYPX_BULKDATA_THRESHOLD").unwrap_or("1024".to_string());
+    s.parse().unwrap_or_else(|_| {
+        panic!("Failed to parse PYPX_BULKDATA_THRESHOLD={s} as an integer")
+    })
+}
+
 fn get_path_env(name: &str) -> PathBuf {
     let s = std::env::var(name)
         .unwrap_or_else(|e| format!("Cannot read environment variable {name}: {e:?}"));
@@ -0,0 +1,157 @@
+//! Streaming `multipart/related` response bodies.
+//!
+//! https://dicom.nema.org/medical/dicom/current/output/html/part18.html#sect_8.7.3.3
+
+use axum::body::{Bytes, StreamBody};
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use futures::stream::{self, BoxStream, Stream, StreamExt, TryStreamExt};
+use std::path::PathBuf;
+use tokio_util::io::ReaderStream;
+
+/// A part of a `multipart/related` response.
+pub(crate) struct Part {
+    content_type: String,
+    body: BoxStream<'static, std::io::Result<Bytes>>,
+}
+
+impl Part {
+    /// A part whose content is read from an (already opened) file.
+    pub fn from_file(content_type: impl Into<String>, file: tokio::fs::File) -> Self {
+        Self {
+            content_type: content_type.into(),
+            body: ReaderStream::new(file).boxed(),
+        }
+    }
+
+    /// A part whose content is already in memory.
+    pub fn from_bytes(content_type: impl Into<String>, bytes: impl Into<Bytes>) -> Self {
+        let bytes = bytes.into();
+        Self {
+            content_type: content_type.into(),
+            body: stream::once(async { Ok(bytes) }).boxed(),
+        }
+    }
+
+    /// A part whose content is read from a file, which is opened when the part is sent.
+    pub fn from_path(content_type: impl Into<String>, path: PathBuf) -> Self {
+        let body = stream::once(tokio::fs::File::open(path))
+            .map_ok(ReaderStream::new)
+            .try_flatten();
+        Self {
+            content_type: content_type.into(),
+            body: body.boxed(),
+        }
+    }
+}
+
+/// Builder of `multipart/related` responses, with a random boundary per response
+/// so that it cannot collide with the content of a part.
+pub(crate) struct MultipartRelated {
+    boundary: String,
+    part_content_type: String,
+}
+
+impl MultipartRelated {
+    /// A response where every part has the given content type, which may have parameters,
+    /// e.g. `application/octet-stream; transfer-syntax=1.2.840.10008.1.2.1`.
+    pub fn new(part_content_type: impl Into<String>) -> Self {
+        Self {
+            boundary: format!("pypx-DICOMweb-{:032x}", fastrand::u128(..)),
+            part_content_type: part_content_type.into(),
+        }
+    }
+
+    /// The value of the `Content-Type` header, e.g.
+    /// `multipart/related; type="application/dicom"; boundary=...`.
+    /// Parameters of the part content type (e.g. `transfer-syntax`) are repeated.
+    pub fn content_type(&self) -> String {
+        let mut params = self.part_content_type.split(';').map(|s| s.trim());
+        let media_type = params.next().unwrap_or_default();
+        let mut content_type = format!("multipart/related; type=\"{media_type}\"");
+        for param in params.filter(|s| !s.is_empty()) {
+            content_type.push_str("; ");
+            content_type.push_str(param);
+        }
+        content_type.push_str("; boundary=");
+        content_type.push_str(&self.boundary);
+        content_type
+    }
+
+    /// Respond with parts as they are produced, so the whole response never needs
+    /// to be held in memory.
+    pub fn response<S>(self, parts: S) -> Response
+    where
+        S: Stream<Item = Part> + Send + 'static,
+    {
+        let headers = [(header::CONTENT_TYPE, self.content_type())];
+        (headers, StreamBody::new(self.body(parts))).into_response()
+    }
+
+    /// Respond with a single part.
+    pub fn response_once(self, part: Part) -> Response {
+        self.response(stream::once(async { part }))
+    }
+
+    fn body<S>(self, parts: S) -> BoxStream<'static, std::io::Result<Bytes>>
+    where
+        S: Stream<Item = Part> + Send + 'static,
+    {
+        let Self { boundary, .. } = self;
+        let end = format!("--{boundary}--\r\n");
+        parts
+            .flat_map(move |part| {
+                let head = format!(
+                    "--{boundary}\r\nContent-Type: {}\r\n\r\n",
+                    part.content_type
+                );
+                stream::once(async move { Ok(Bytes::from(head)) })
+                    .chain(part.body)
+                    .chain(stream::once(async { Ok(Bytes::from_static(b"\r\n")) }))
+            })
+            .chain(stream::once(async move { Ok(Bytes::from(end)) }))
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case("application/dicom", "multipart/related; type=\"application/dicom\"")]
+    #[case(
+        "application/octet-stream;transfer-syntax=1.2.840.10008.1.2.1",
+        "multipart/related; type=\"application/octet-stream\"; transfer-syntax=1.2.840.10008.1.2.1"
+    )]
+    fn test_content_type(#[case] part_content_type: &str, #[case] expected_prefix: &str) {
+        let multipart = MultipartRelated::new(part_content_type);
+        let expected = format!("{expected_prefix}; boundary={}", multipart.boundary);
+        assert_eq!(multipart.content_type(), expected)
+    }
+
+    #[rstest]
+    fn test_boundary_is_random() {
+        assert_ne!(
+            MultipartRelated::new("image/jpeg").boundary,
+            MultipartRelated::new("image/jpeg").boundary
+        )
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_body() {
+        let multipart = MultipartRelated::new("text/plain");
+        let boundary = multipart.boundary.clone();
+        let parts = stream::iter(["hello", "world"]).map(|s| Part::from_bytes("text/plain", s));
+        let chunks: Vec<_> = multipart.body(parts).try_collect().await.unwrap();
+        let body = String::from_utf8(chunks.concat()).unwrap();
+        let expected = format!(
+            "--{boundary}\r\nContent-Type: text/plain\r\n\r\nhello\r\n\
+             --{boundary}\r\nContent-Type: text/plain\r\n\r\nworld\r\n\
+             --{boundary}--\r\n"
+        );
+        assert_eq!(body, expected)
+    }
+}
@@ -1,15 +1,25 @@
 //! Reads data from a pypx-organized directory, presenting it in "DICOMweb format."
 
-use crate::constants;
-use crate::dicom::dicomfile2json;
-use crate::errors::{FileError, PypxBaseNotADir, ReadDirError};
+use crate::dicom::{dicomfile2json, dicomfile_attributes, read_bulkdata, BulkData};
+use crate::errors::{FileError, IndexError, PypxBaseNotADir, ReadDirError};
+use crate::index::PypxIndex;
 use crate::json_files::{read_1member_json_file, read_json_file};
-use crate::translate::{series_meta_to_dicomweb, study_meta_to_dicomweb};
-use futures::{pin_mut, StreamExt};
-use pypx::{InstanceData, StudyDataMeta, StudyDataSeriesMeta};
-use serde_json::Value;
-use std::collections::HashMap;
+use crate::qido::{IncludeField, Page, Pagination, QidoQuery};
+use crate::rendered::RenderOptions;
+use crate::thumbnail::ThumbnailCache;
+use crate::translate::{
+    instance_to_dicomweb, merge_dicomweb, patient_studies_to_dicomweb, patient_to_dicomweb,
+    series_meta_to_dicomweb, study_meta_to_dicomweb, study_summary_to_dicomweb,
+};
+use dicom::core::Tag;
+use dicom::dictionary_std::tags;
+use futures::{pin_mut, StreamExt, TryStreamExt};
+use pypx::{InstanceData, PatientData, StudyDataMeta, StudyDataSeriesMeta};
+use serde_json::{Map, Value};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::future::Future;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use tokio_stream::wrappers::ReadDirStream;
 use tracing::{event, Level};
 
@@ -31,26 +41,43 @@ macro_rules! select_files_by_extension {
 }
 
 /// Reader of a pypx-organized directory of DICOM and JSON files.
-pub struct PypxReader {
+pub(crate) struct PypxReader {
     study_data_dir: PathBuf,
     series_data_dir: PathBuf,
+    patient_data_dir: PathBuf,
     data_dir: PathBuf,
 
     /// Path where the data directory is mounted for the repacker
     /// (`rx-repack`, which is called by `storescp`)
     repack_data_dir_mountpath: PathBuf,
+
+    /// Binary attributes larger than this many bytes are omitted from metadata
+    /// responses, and are instead referenced by a `BulkDataURI`.
+    bulkdata_threshold: usize,
+
+    thumbnails: ThumbnailCache,
+
+    /// Attributes of the representative instance of studies and series, for `includefield`.
+    attributes_cache: AttributesCache,
+
+    /// Optional index of the log directory, which is queried instead of reading its files.
+    index: Option<PypxIndex>,
 }
 
 impl PypxReader {
     /// Instantiate a [PypxReader], checking to make sure the right directories exist
-    /// (`log/studyData`, `log/seriesData`).
+    /// (`log/studyData`, `log/seriesData`). `log/patientData` is optional.
     pub fn new(
         log_dir: &Path,
         data_dir: PathBuf,
         repack_data_dir_mountpath: PathBuf,
+        bulkdata_threshold: usize,
+        thumbnail_dir: PathBuf,
+        index: Option<PypxIndex>,
     ) -> Result<Self, PypxBaseNotADir> {
         let study_data_dir = log_dir.join("studyData");
         let series_data_dir = log_dir.join("seriesData");
+        let patient_data_dir = log_dir.join("patientData");
 
         let all = [&study_data_dir, &series_data_dir];
         if !all.iter().all(|p| p.is_dir()) {
@@ -59,8 +86,13 @@ impl PypxReader {
             Ok(Self {
                 study_data_dir,
                 series_data_dir,
+                patient_data_dir,
                 data_dir,
                 repack_data_dir_mountpath,
+                bulkdata_threshold,
+                thumbnails: ThumbnailCache::new(thumbnail_dir),
+                attributes_cache: AttributesCache::default(),
+                index,
             })
         }
     }
@@ -69,28 +101,461 @@ impl PypxReader {
     /// Returns data in DICOMweb's response schema.
     pub async fn query_studies(
         &self,
-        query: &HashMap<String, String>,
-        limit: usize,
-    ) -> Result<Vec<Value>, FileError> {
-        if limit == 0 {
-            return Ok(vec![]);
+        query: &QidoQuery,
+        pagination: Pagination,
+        include: &IncludeField,
+    ) -> Result<Page<Value>, FileError> {
+        let indexed = self
+            .query_index(|index| index.query_studies(query, pagination))
+            .await;
+        let (page, patients) = match indexed {
+            Some(page) => (page, Patients::new()),
+            None => {
+                let studies = self.find_studies(query).await?;
+                let page = pagination.paginate(studies);
+                let studies = self.summarize_studies(page.items).await;
+                let patients = self.get_patients(&studies).await;
+                let page = Page {
+                    items: studies,
+                    truncated: page.truncated,
+                };
+                (page, patients)
+            }
+        };
+        let items = futures::stream::iter(&page.items)
+            .map(|study| {
+                let of = Representative::Study(&study.meta.StudyInstanceUID);
+                self.include_fields(study.to_dicomweb(&patients), include, of)
+            })
+            .boxed()
+            .buffered(4)
+            .collect()
+            .await;
+        Ok(Page {
+            items,
+            truncated: page.truncated,
+        })
+    }
+
+    /// Find patients (from `log/patientData`) which match the given query.
+    /// Patients are sorted by `PatientID`.
+    pub async fn query_patients(
+        &self,
+        query: &QidoQuery,
+        pagination: Pagination,
+    ) -> Result<Page<Value>, ReadDirError> {
+        let path = &self.patient_data_dir;
+        let read_dir = tokio::fs::read_dir(path)
+            .await
+            .map_err(|e| ReadDirError(path.to_path_buf(), e.kind()));
+        let read_dir = match read_dir {
+            Ok(read_dir) => read_dir,
+            Err(ReadDirError(_, std::io::ErrorKind::NotFound)) => {
+                return Ok(pagination.paginate(vec![]))
+            }
+            Err(e) => return Err(e),
+        };
+        let mut patients: Vec<PatientData<'static>> = ReadDirStream::new(read_dir)
+            .filter_map(report_then_discard_error)
+            .map(|entry| entry.path())
+            .filter_map(select_files_by_extension!(".json"))
+            .map(read_1member_json_file::<_, PatientData<'static>>)
+            .buffer_unordered(4)
+            .filter_map(report_then_discard_error)
+            .boxed()
+            .filter(|patient| futures::future::ready(query.matches(patient)))
+            .collect()
+            .await;
+        patients.sort_unstable_by(|a, b| a.PatientID.cmp(&b.PatientID));
+        Ok(pagination.paginate(patients).map(|patient| {
+            merge_dicomweb(
+                patient_to_dicomweb(&patient),
+                patient_studies_to_dicomweb(&patient),
+            )
+        }))
+    }
+
+    /// Find the series of a study which match the given query.
+    pub async fn query_series(
+        &self,
+        study_instance_uid: &str,
+        query: &QidoQuery,
+        pagination: Pagination,
+        include: &IncludeField,
+    ) -> Result<Page<Value>, ReadDirError> {
+        let indexed = self
+            .query_index(|index| index.query_series(Some(study_instance_uid), query, pagination))
+            .await;
+        let page = match indexed {
+            Some(page) => page.map(|(_, series, num_instances)| (series, Some(num_instances))),
+            None => {
+                let series = self.find_series(study_instance_uid, query).await?;
+                pagination.paginate(series).map(|series| (series, None))
+            }
+        };
+        let items = futures::stream::iter(page.items)
+            .map(|(series, num_instances)| {
+                self.get_series_data_including(series, num_instances, include)
+            })
+            .boxed()
+            .buffered(4)
+            .collect()
+            .await;
+        Ok(Page {
+            items,
+            truncated: page.truncated,
+        })
+    }
+
+    /// Find series across all studies which match the given query.
+    /// The attributes of each series' study are included in the response.
+    pub async fn query_all_series(
+        &self,
+        query: &QidoQuery,
+        pagination: Pagination,
+        include: &IncludeField,
+    ) -> Result<Page<Value>, FileError> {
+        let indexed = self
+            .query_index(|index| index.query_series(None, query, pagination))
+            .await;
+        if let Some(page) = indexed {
+            let patients = Patients::new();
+            let patients = &patients;
+            let items = futures::stream::iter(page.items)
+                .map(|(study, series, num_instances)| async move {
+                    let series = self
+                        .get_series_data_including(series, Some(num_instances), include)
+                        .await;
+                    merge_dicomweb(study.to_dicomweb(patients), series)
+                })
+                .boxed()
+                .buffered(4)
+                .collect()
+                .await;
+            return Ok(Page {
+                items,
+                truncated: page.truncated,
+            });
+        }
+        let studies = without_summaries(self.find_studies(query).await?);
+        let series = self.find_series_of_studies(&studies, query).await;
+        let page = pagination.paginate(series);
+        let patients = self
+            .get_patients(page.items.iter().map(|(study, _)| *study))
+            .await;
+        let patients = &patients;
+        let items = futures::stream::iter(page.items)
+            .map(|(study, series)| async move {
+                let series = self.get_series_data_including(series, None, include).await;
+                merge_dicomweb(study.to_dicomweb(patients), series)
+            })
+            .boxed()
+            .buffered(4)
+            .collect()
+            .await;
+        Ok(Page {
+            items,
+            truncated: page.truncated,
+        })
+    }
+
+    /// Find instances across all studies and series which match the given query.
+    /// The attributes of each instance's series and study are included in the response.
+    ///
+    /// Series are listed lazily: no more series are listed once enough instances were
+    /// found for the page, see [Pagination::needed].
+    pub async fn query_all_instances(
+        &self,
+        query: &QidoQuery,
+        pagination: Pagination,
+        include: &IncludeField,
+        base_url: &str,
+    ) -> Result<Page<Value>, FileError> {
+        let indexed = self
+            .query_index(|index| index.query_instances(None, None, query, pagination))
+            .await;
+        if let Some(page) = indexed {
+            let patients = Patients::new();
+            let items = futures::stream::iter(&page.items)
+                .map(|found| {
+                    let series_dicomweb =
+                        series_meta_to_dicomweb(&found.series, found.num_instances);
+                    let study_instance_uid = &found.study.meta.StudyInstanceUID;
+                    let dcm = merge_dicomweb(
+                        merge_dicomweb(found.study.to_dicomweb(&patients), series_dicomweb),
+                        found
+                            .instance
+                            .to_dicomweb(study_instance_uid, &found.series, base_url),
+                    );
+                    let of = Representative::Instance(
+                        &found.series.SeriesInstanceUID,
+                        &found.instance.sop_instance_uid,
+                    );
+                    self.include_fields(dcm, include, of)
+                })
+                .boxed()
+                .buffered(4)
+                .collect()
+                .await;
+            return Ok(Page {
+                items,
+                truncated: page.truncated,
+            });
+        }
+        let studies = without_summaries(self.find_studies(query).await?);
+        let series = self.find_series_of_studies(&studies, query).await;
+        let stream = futures::stream::iter(&series)
+            .map(|(study, series)| async move {
+                self.find_instances(&series.SeriesInstanceUID, query)
+                    .await
+                    .map(|(instances, num_instances)| {
+                        let series_dicomweb = series_meta_to_dicomweb(series, num_instances);
+                        (*study, series, series_dicomweb, instances)
+                    })
+            })
+            .boxed()
+            .buffered(4)
+            .filter_map(report_then_discard_error);
+        pin_mut!(stream);
+        let mut series_with_instances = Vec::new();
+        let mut count = 0;
+        while count < pagination.needed() {
+            match stream.next().await {
+                Some(found) => {
+                    count += found.3.len();
+                    series_with_instances.push(found);
+                }
+                None => break,
+            }
         }
-        // TODO add PatientName to the data
-        let studies = if let Some(study_instance_uid) = query.get("StudyInstanceUID") {
+        let instances = series_with_instances
+            .iter()
+            .flat_map(|(study, series, series_dicomweb, instances)| {
+                instances
+                    .iter()
+                    .map(move |i| (*study, series, series_dicomweb, i))
+            })
+            .collect();
+        let page = pagination.paginate(instances);
+        let patients = self
+            .get_patients(page.items.iter().map(|(study, ..)| *study))
+            .await;
+        let items = futures::stream::iter(page.items)
+            .map(|(study, series, series_dicomweb, instance)| {
+                let dcm = merge_dicomweb(
+                    merge_dicomweb(study.to_dicomweb(&patients), series_dicomweb.clone()),
+                    instance.to_dicomweb(&study.meta.StudyInstanceUID, series, base_url),
+                );
+                let of =
+                    Representative::Instance(&series.SeriesInstanceUID, &instance.sop_instance_uid);
+                self.include_fields(dcm, include, of)
+            })
+            .boxed()
+            .buffered(4)
+            .collect()
+            .await;
+        Ok(Page {
+            items,
+            truncated: page.truncated,
+        })
+    }
+
+    /// Find the instances of a series which match the given query.
+    pub async fn query_instances(
+        &self,
+        study_instance_uid: &str,
+        series_instance_uid: &str,
+        query: &QidoQuery,
+        pagination: Pagination,
+        include: &IncludeField,
+        base_url: &str,
+    ) -> Result<Page<Value>, FileError> {
+        let indexed = self
+            .query_index(|index| {
+                let (study, series) = (Some(study_instance_uid), Some(series_instance_uid));
+                index.query_instances(study, series, query, pagination)
+            })
+            .await;
+        if let Some(page) = indexed {
+            let items = futures::stream::iter(&page.items)
+                .map(|found| {
+                    let dcm =
+                        found
+                            .instance
+                            .to_dicomweb(study_instance_uid, &found.series, base_url);
+                    let of = Representative::Instance(
+                        series_instance_uid,
+                        &found.instance.sop_instance_uid,
+                    );
+                    self.include_fields(dcm, include, of)
+                })
+                .boxed()
+                .buffered(4)
+                .collect()
+                .await;
+            return Ok(Page {
+                items,
+                truncated: page.truncated,
+            });
+        }
+        let series_meta_file =
+            self.studydata_series_meta_file_for(study_instance_uid, series_instance_uid);
+        let series: StudyDataSeriesMeta = read_1member_json_file(&series_meta_file).await?;
+        let instances = if query.matches(&series) {
+            self.find_instances(series_instance_uid, query).await?.0
+        } else {
+            vec![]
+        };
+        let page = pagination.paginate(instances);
+        let items = futures::stream::iter(&page.items)
+            .map(|instance| {
+                let dcm = instance.to_dicomweb(study_instance_uid, &series, base_url);
+                let of = Representative::Instance(series_instance_uid, &instance.sop_instance_uid);
+                self.include_fields(dcm, include, of)
+            })
+            .boxed()
+            .buffered(4)
+            .collect()
+            .await;
+        Ok(Page {
+            items,
+            truncated: page.truncated,
+        })
+    }
+
+    /// Find all studies matching a given filter.
+    ///
+    /// The `patientData` of the studies is only read if the query matches attributes
+    /// of the patient, see [PypxReader::get_patients] for reading it otherwise.
+    /// Likewise, their [StudySummary] is only computed if the query matches its attributes.
+    ///
+    /// Studies are sorted by `StudyDate` (most recent first) then by `StudyInstanceUID`
+    /// so that results are stable across paginated requests.
+    async fn find_studies(&self, query: &QidoQuery) -> Result<Vec<Study>, FileError> {
+        let studies = if let Some(study_instance_uid) = query.single_value(tags::STUDY_INSTANCE_UID)
+        {
             flatten_notfound_error(self.get_study(study_instance_uid).await)?
+                .into_iter()
+                .filter(|study| query.matches(study))
+                .collect()
         } else {
-            self.ls_studies(query, limit).await
+            self.ls_studies(query).await
         };
-        let dicomweb_response = studies.iter().map(study_meta_to_dicomweb).collect();
-        Ok(dicomweb_response)
+        let mut studies: Vec<_> = studies
+            .into_iter()
+            .map(|meta| Study {
+                meta,
+                patient: None,
+                summary: None,
+            })
+            .collect();
+        // studies were matched by their own attributes, now match by their patient's.
+        // Reading the patientData of every study is slow, so it is only done if necessary
+        if PATIENT_ONLY_TAGS.iter().any(|tag| query.has_key(*tag)) {
+            let patients = self.get_patients(&studies).await;
+            studies = studies
+                .into_iter()
+                .map(|mut study| {
+                    study.patient = patients.get(study.meta.PatientID.as_ref()).cloned();
+                    study
+                })
+                .filter(|study| query.matches(study))
+                .collect();
+        }
+        // aggregating the series of every study is slow, so it is only done if necessary
+        if STUDY_SUMMARY_TAGS.iter().any(|tag| query.has_key(*tag)) {
+            studies = self
+                .summarize_studies(studies)
+                .await
+                .into_iter()
+                .filter(|study| query.matches(study))
+                .collect();
+        }
+        studies.sort_unstable_by(|a, b| {
+            b.meta
+                .StudyDate
+                .cmp(&a.meta.StudyDate)
+                .then_with(|| a.meta.StudyInstanceUID.cmp(&b.meta.StudyInstanceUID))
+        });
+        Ok(studies)
+    }
+
+    /// Get the `patientData` of the patients of studies which do not have it yet.
+    /// Patients are read once even if they have several studies.
+    async fn get_patients<'a>(&self, studies: impl IntoIterator<Item = &'a Study>) -> Patients {
+        let patient_ids: HashSet<String> = studies
+            .into_iter()
+            .filter(|s| s.patient.is_none())
+            .map(|s| s.meta.PatientID.to_string())
+            .collect();
+        futures::stream::iter(patient_ids)
+            .map(|patient_id| async move {
+                let patient = flatten_notfound_error(self.get_patient(&patient_id).await)?;
+                Ok::<_, FileError>(
+                    patient
+                        .into_iter()
+                        .next()
+                        .map(|p| (patient_id, Arc::new(p))),
+                )
+            })
+            .buffer_unordered(4)
+            .filter_map(report_then_discard_error)
+            // boxing works around "error: higher-ranked lifetime error"
+            .boxed()
+            .filter_map(|patient| async move { patient })
+            .collect()
+            .await
     }
 
-    /// Find studies matching a given filter, returning study metadata as a DICOMweb response.
-    async fn ls_studies<'a>(
-        &'a self,
-        query: &'a HashMap<String, String>,
-        limit: usize,
-    ) -> Vec<StudyDataMeta> {
+    /// Compute the [StudySummary] of studies which do not have one yet.
+    async fn summarize_studies(&self, studies: Vec<Study>) -> Vec<Study> {
+        futures::stream::iter(studies)
+            .map(|mut study| async move {
+                if study.summary.is_none() {
+                    let summary = self.summarize_study(&study.meta.StudyInstanceUID).await;
+                    study.summary = Some(summary);
+                }
+                study
+            })
+            .boxed()
+            .buffered(4)
+            .collect()
+            .await
+    }
+
+    /// Aggregate the attributes of the series of a study, and count its instances.
+    async fn summarize_study(&self, study_instance_uid: &str) -> StudySummary {
+        let series = self
+            .find_series(study_instance_uid, &QidoQuery::default())
+            .await
+            .unwrap_or_else(|e| {
+                event!(Level::WARN, "{:?}", e);
+                vec![]
+            });
+        let series_instance_uids: Vec<_> = series
+            .iter()
+            .map(|s| s.SeriesInstanceUID.to_string())
+            .collect();
+        let num_instances = futures::stream::iter(series_instance_uids)
+            .map(|uid| async move { self.count_instances(&uid).await })
+            .buffer_unordered(4)
+            .filter_map(report_then_discard_error)
+            .fold(0, |total, count| async move { total + count })
+            .await;
+        StudySummary::new(&series, num_instances)
+    }
+
+    /// Get the `log/patientData/{PatientID}.json` of a patient.
+    async fn get_patient(&self, patient_id: &str) -> Result<PatientData<'static>, FileError> {
+        let path = self.patient_data_dir.join(format!("{patient_id}.json"));
+        if patient_id.is_empty() || patient_id.starts_with('.') || patient_id.contains('/') {
+            return Err(FileError::NotFound(path));
+        }
+        read_1member_json_file(&path).await
+    }
+
+    /// Find all studies matching a given filter, in no particular order.
+    async fn ls_studies(&self, query: &QidoQuery) -> Vec<StudyDataMeta<'static>> {
         let path = &self.study_data_dir;
         let read_dir = tokio::fs::read_dir(path)
             .await
@@ -106,47 +571,255 @@ impl PypxReader {
             .filter_map(report_then_discard_error)
             .filter_map(|study| study_matches_wrapper(study, query));
 
-        // causes "error: higher-ranked lifetime error"
-        // stream
-        //     .zip(futures::stream::iter(0..limit))
-        //     .filter_map(|(x, _)| async move { Some(x) })
-        //     .collect()
-        //     .await
-
+        // StreamExt::collect causes "error: higher-ranked lifetime error"
         pin_mut!(stream);
-        let mut data = Vec::with_capacity(limit.min(200));
+        let mut data = Vec::new();
         while let Some(next) = stream.next().await {
             data.push(next);
-            if data.len() >= limit {
-                return data;
-            }
         }
         data
     }
 
+    /// Run a query on the index, if there is one. If the query fails, the error is
+    /// logged and `None` is returned, so that the files are read instead.
+    async fn query_index<'a, T, F, Fut>(&'a self, f: F) -> Option<T>
+    where
+        F: FnOnce(&'a PypxIndex) -> Fut,
+        Fut: Future<Output = Result<T, IndexError>>,
+    {
+        match f(self.index.as_ref()?).await {
+            Ok(value) => Some(value),
+            Err(e) => {
+                event!(Level::ERROR, "{:?}", e);
+                None
+            }
+        }
+    }
+
     /// Get a single study and its metadata.
-    async fn get_study(&self, study_instance_uid: &str) -> Result<StudyDataMeta, FileError> {
+    async fn get_study(
+        &self,
+        study_instance_uid: &str,
+    ) -> Result<StudyDataMeta<'static>, FileError> {
         let file = self.study_meta_file_for(study_instance_uid);
         let result: Result<StudyDataMeta, _> = read_study_meta_json(file).await;
         result
     }
 
-    /// List the series of a study.
-    pub async fn get_series(&self, study_instance_uid: &str) -> Result<Vec<Value>, ReadDirError> {
+    /// Find the series of a study which match the given query.
+    ///
+    /// Series are sorted by `SeriesNumber` then by `SeriesInstanceUID`.
+    async fn find_series(
+        &self,
+        study_instance_uid: &str,
+        query: &QidoQuery,
+    ) -> Result<Vec<StudyDataSeriesMeta<'static>>, ReadDirError> {
+        let indexed = self
+            .query_index(|index| {
+                index.query_series(Some(study_instance_uid), query, Pagination::default())
+            })
+            .await;
+        if let Some(page) = indexed {
+            return Ok(page
+                .items
+                .into_iter()
+                .map(|(_, series, _)| series)
+                .collect());
+        }
         let path = self.series_meta_dir_of(study_instance_uid);
         let read_dir = tokio::fs::read_dir(&path)
             .await
             .map_err(|e| ReadDirError(path, e.kind()))?;
-        let datas = ReadDirStream::new(read_dir)
+        let mut series: Vec<StudyDataSeriesMeta<'static>> = ReadDirStream::new(read_dir)
             .filter_map(report_then_discard_error)
             .map(|entry| entry.path())
             .filter_map(select_files_by_extension!("-meta.json"))
-            .map(|path| self.get_series_data(path))
+            .map(read_1member_json_file::<_, StudyDataSeriesMeta<'static>>)
             .buffer_unordered(4)
             .filter_map(report_then_discard_error)
+            // boxing works around "error: higher-ranked lifetime error"
+            .boxed()
+            .filter(|series| futures::future::ready(query.matches(series)))
+            .collect()
+            .await;
+        series.sort_unstable_by(|a, b| {
+            series_number_of(a)
+                .cmp(&series_number_of(b))
+                .then_with(|| a.SeriesInstanceUID.cmp(&b.SeriesInstanceUID))
+        });
+        Ok(series)
+    }
+
+    /// Find the series of several studies which match the given query.
+    /// The order of `studies` is preserved.
+    async fn find_series_of_studies<'a>(
+        &self,
+        studies: &'a [Study],
+        query: &QidoQuery,
+    ) -> Vec<(&'a Study, StudyDataSeriesMeta<'static>)> {
+        let series_of_studies: Vec<_> = futures::stream::iter(studies)
+            .map(|study| async move {
+                self.find_series(&study.meta.StudyInstanceUID, query)
+                    .await
+                    .map(|series| (study, series))
+            })
+            .boxed()
+            .buffered(4)
+            .filter_map(report_then_discard_error)
+            .collect()
+            .await;
+        series_of_studies
+            .into_iter()
+            .flat_map(|(study, series)| series.into_iter().map(move |s| (study, s)))
+            .collect()
+    }
+
+    /// Find the instances of a series which match the given query. Only the names of
+    /// the files in `log/seriesData/{series_instance_uid}-img` are read.
+    ///
+    /// Instances are sorted by `InstanceNumber` then by `SOPInstanceUID`.
+    /// Also returns the total number of instances in the series.
+    async fn find_instances(
+        &self,
+        series_instance_uid: &str,
+        query: &QidoQuery,
+    ) -> Result<(Vec<InstanceFile>, usize), ReadDirError> {
+        let indexed = self
+            .query_index(|index| index.find_instances(series_instance_uid, query))
+            .await;
+        if let Some(found) = indexed {
+            return Ok(found);
+        }
+        let all_instances = self.ls_instances(series_instance_uid).await?;
+        let num_instances = all_instances.len();
+        let mut instances: Vec<_> = all_instances
+            .into_iter()
+            .filter(|instance| query.matches(instance))
+            .collect();
+        instances.sort_unstable_by(|a, b| {
+            a.instance_number
+                .parse::<i64>()
+                .ok()
+                .cmp(&b.instance_number.parse().ok())
+                .then_with(|| a.sop_instance_uid.cmp(&b.sop_instance_uid))
+        });
+        Ok((instances, num_instances))
+    }
+
+    /// List the instances of a series, from the names of the files in
+    /// `log/seriesData/{series_instance_uid}-img`.
+    async fn ls_instances(
+        &self,
+        series_instance_uid: &str,
+    ) -> Result<Vec<InstanceFile>, ReadDirError> {
+        let path = self.instances_json_dir_for(series_instance_uid);
+        let read_dir = tokio::fs::read_dir(&path)
+            .await
+            .map_err(|e| ReadDirError(path, e.kind()))?;
+        let instances = ReadDirStream::new(read_dir)
+            .filter_map(report_then_discard_error)
+            .map(|entry| entry.path())
+            .filter_map(select_files_by_extension!(".dcm.json"))
+            .filter_map(|path| async move { InstanceFile::from_path(&path) })
             .collect()
             .await;
-        Ok(datas)
+        Ok(instances)
+    }
+
+    /// Get a thumbnail of a study, which is the thumbnail of its series having the most
+    /// instances. Returns `None` if the study has no instances.
+    pub async fn get_study_thumbnail(
+        &self,
+        study_instance_uid: &str,
+        options: RenderOptions,
+    ) -> Result<Option<Vec<u8>>, FileError> {
+        let series = self
+            .find_series(study_instance_uid, &QidoQuery::default())
+            .await?;
+        let instances_of_series: Vec<_> = futures::stream::iter(&series)
+            .map(|series| async move {
+                self.find_instances(&series.SeriesInstanceUID, &QidoQuery::default())
+                    .await
+                    .map(|(instances, _)| (series, instances))
+            })
+            .boxed()
+            .buffered(4)
+            .filter_map(report_then_discard_error)
+            .collect()
+            .await;
+        // max_by_key returns the last maximum, so reverse to prefer the first series
+        let representative = instances_of_series
+            .into_iter()
+            .rev()
+            .max_by_key(|(_, instances)| instances.len());
+        if let Some((series, instances)) = representative {
+            self.get_representative_thumbnail(&series.SeriesInstanceUID, instances, options)
+                .await
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Get a thumbnail of a series, which is the thumbnail of its middle instance.
+    /// Returns `None` if the series has no instances.
+    pub async fn get_series_thumbnail(
+        &self,
+        series_instance_uid: &str,
+        options: RenderOptions,
+    ) -> Result<Option<Vec<u8>>, FileError> {
+        let (instances, _) = self
+            .find_instances(series_instance_uid, &QidoQuery::default())
+            .await?;
+        self.get_representative_thumbnail(series_instance_uid, instances, options)
+            .await
+    }
+
+    /// Get a thumbnail of an instance. Thumbnails are cached on disk.
+    pub async fn get_instance_thumbnail(
+        &self,
+        series_instance_uid: &str,
+        sop_instance_uid: &str,
+        options: RenderOptions,
+    ) -> Result<Option<Vec<u8>>, FileError> {
+        let path = self
+            .get_instance_fslocation(series_instance_uid, sop_instance_uid)
+            .await?;
+        self.thumbnails
+            .get_or_render(path, series_instance_uid, sop_instance_uid, options)
+            .await
+    }
+
+    /// Get the thumbnail of the middle slice of a series, given its instances sorted by
+    /// `InstanceNumber`.
+    async fn get_representative_thumbnail(
+        &self,
+        series_instance_uid: &str,
+        instances: Vec<InstanceFile>,
+        options: RenderOptions,
+    ) -> Result<Option<Vec<u8>>, FileError> {
+        if let Some(instance) = instances.get(instances.len() / 2) {
+            self.get_instance_thumbnail(series_instance_uid, &instance.sop_instance_uid, options)
+                .await
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Serialize all DICOMs of a study into JSON.
+    pub async fn get_study_dicomweb_metadata(
+        &self,
+        study_instance_uid: &str,
+        base_url: &str,
+    ) -> Result<Vec<Value>, FileError> {
+        let files = self.get_study_dcm_files(study_instance_uid).await?;
+        let bulkdata = self.bulkdata(base_url);
+        let dcms = futures::stream::iter(files)
+            .map(|path| dicomfile2json(path, bulkdata.clone()))
+            .buffer_unordered(4)
+            .filter_map(report_then_discard_error)
+            .collect()
+            .await;
+        Ok(dcms)
     }
 
     /// Serialize all DICOMs of a series into JSON.
@@ -154,11 +827,13 @@ impl PypxReader {
         &self,
         study_instance_uid: &str,
         series_instance_uid: &str,
+        base_url: &str,
     ) -> Result<Vec<Value>, FileError> {
+        let bulkdata = self.bulkdata(base_url);
         let dcms = self
             .ls_dcm(study_instance_uid, series_instance_uid)
             .await?
-            .map(dicomfile2json)
+            .map(|path| dicomfile2json(path, bulkdata.clone()))
             .buffer_unordered(4)
             .filter_map(report_then_discard_error)
             .collect()
@@ -166,6 +841,72 @@ impl PypxReader {
         Ok(dcms)
     }
 
+    /// Serialize a DICOM instance into JSON.
+    pub async fn get_instance_dicomweb_metadata(
+        &self,
+        series_instance_uid: &str,
+        sop_instance_uid: &str,
+        base_url: &str,
+    ) -> Result<Value, FileError> {
+        let path = self
+            .get_instance_fslocation(series_instance_uid, sop_instance_uid)
+            .await?;
+        dicomfile2json(path, self.bulkdata(base_url)).await
+    }
+
+    /// Get the raw bytes of a binary attribute of a DICOM instance,
+    /// as referenced by a `BulkDataURI`.
+    pub async fn get_instance_bulkdata(
+        &self,
+        series_instance_uid: &str,
+        sop_instance_uid: &str,
+        tag_path: String,
+    ) -> Result<Option<Vec<u8>>, FileError> {
+        let path = self
+            .get_instance_fslocation(series_instance_uid, sop_instance_uid)
+            .await?;
+        read_bulkdata(path, tag_path).await
+    }
+
+    fn bulkdata(&self, base_url: &str) -> BulkData {
+        BulkData {
+            base_url: base_url.to_string(),
+            threshold: self.bulkdata_threshold,
+        }
+    }
+
+    /// List the DICOM files of a series, sorted by file name.
+    pub async fn get_series_dcm_files(
+        &self,
+        study_instance_uid: &str,
+        series_instance_uid: &str,
+    ) -> Result<Vec<PathBuf>, FileError> {
+        let mut files: Vec<PathBuf> = self
+            .ls_dcm(study_instance_uid, series_instance_uid)
+            .await?
+            .collect()
+            .await;
+        files.sort_unstable();
+        Ok(files)
+    }
+
+    /// List the DICOM files of every series of a study.
+    pub async fn get_study_dcm_files(
+        &self,
+        study_instance_uid: &str,
+    ) -> Result<Vec<PathBuf>, FileError> {
+        let series = self
+            .find_series(study_instance_uid, &QidoQuery::default())
+            .await?;
+        let files: Vec<Vec<PathBuf>> = futures::stream::iter(&series)
+            .map(|series| self.get_series_dcm_files(study_instance_uid, &series.SeriesInstanceUID))
+            .boxed()
+            .buffered(4)
+            .try_collect()
+            .await?;
+        Ok(files.into_iter().flatten().collect())
+    }
+
     /// Get `FSlocation` from the JSON file which describes a DICOM instance file.
     pub async fn get_instance_fslocation(
         &self,
@@ -210,18 +951,131 @@ impl PypxReader {
     // Helper functions for getting information from files and directories
     // --------------------------------------------------------------------------------
 
-    /// Given a path `log/studyData/XXX-series/X-meta.json`, produce the metadata of the
-    /// corresponding series including `NumberOfSeriesRelatedInstances`.
-    async fn get_series_data(&self, path: PathBuf) -> Result<Value, FileError> {
-        let data: StudyDataSeriesMeta = read_1member_json_file(&path).await?;
-        let series_instance_uid = data.SeriesInstanceUID.as_ref();
-        let num_instances = self.count_instances(series_instance_uid).await.unwrap_or(0);
-        let value = series_meta_to_dicomweb(&data, num_instances);
-        Ok::<Value, FileError>(value)
+    /// Given the contents of a file `log/studyData/XXX-series/X-meta.json`, produce the
+    /// metadata of the corresponding series including `NumberOfSeriesRelatedInstances`,
+    /// which is counted unless it is given.
+    async fn get_series_data(
+        &self,
+        data: StudyDataSeriesMeta<'static>,
+        num_instances: Option<usize>,
+    ) -> Value {
+        let num_instances = match num_instances {
+            Some(num_instances) => num_instances,
+            None => {
+                let series_instance_uid = data.SeriesInstanceUID.as_ref();
+                self.count_instances(series_instance_uid).await.unwrap_or(0)
+            }
+        };
+        series_meta_to_dicomweb(&data, num_instances)
+    }
+
+    /// Same as [Self::get_series_data], plus the attributes requested by `includefield`.
+    async fn get_series_data_including(
+        &self,
+        data: StudyDataSeriesMeta<'static>,
+        num_instances: Option<usize>,
+        include: &IncludeField,
+    ) -> Value {
+        let series_instance_uid = data.SeriesInstanceUID.to_string();
+        let dcm = self.get_series_data(data, num_instances).await;
+        self.include_fields(dcm, include, Representative::Series(&series_instance_uid))
+            .await
+    }
+
+    /// Add the attributes requested by `includefield` to a result. Attributes are taken
+    /// from the header of a representative DICOM instance of the result, because
+    /// `pypx` only records a few of them in its JSON files.
+    async fn include_fields(
+        &self,
+        dcm: Value,
+        include: &IncludeField,
+        of: Representative<'_>,
+    ) -> Value {
+        let mut dcm = match dcm {
+            Value::Object(dcm) if !include.is_none() => dcm,
+            _ => return dcm,
+        };
+        let (attributes, allowed) = match of {
+            Representative::Study(uid) => (
+                self.study_attributes(uid).await,
+                Some(&STUDY_LEVEL_TAGS[..]),
+            ),
+            Representative::Series(uid) => (
+                self.series_attributes(uid).await,
+                Some(&SERIES_LEVEL_TAGS[..]),
+            ),
+            Representative::Instance(series_instance_uid, sop_instance_uid) => {
+                let attributes = self
+                    .instance_attributes(series_instance_uid, sop_instance_uid)
+                    .await
+                    .map(Arc::new);
+                (attributes, None)
+            }
+        };
+        match attributes {
+            Ok(attributes) => include.include(&mut dcm, &attributes, allowed),
+            Err(e) => event!(Level::WARN, "{:?}", e),
+        }
+        Value::Object(dcm)
+    }
+
+    /// Get the attributes of the first instance of the first series of a study.
+    async fn study_attributes(&self, study_instance_uid: &str) -> Result<Attributes, FileError> {
+        if let Some(attributes) = self.attributes_cache.get(study_instance_uid) {
+            return Ok(attributes);
+        }
+        let series = self
+            .find_series(study_instance_uid, &QidoQuery::default())
+            .await?;
+        let first = series
+            .first()
+            .ok_or_else(|| FileError::NotFound(self.series_meta_dir_of(study_instance_uid)))?;
+        let attributes = self.series_attributes(&first.SeriesInstanceUID).await?;
+        self.attributes_cache
+            .insert(study_instance_uid, Arc::clone(&attributes));
+        Ok(attributes)
+    }
+
+    /// Get the attributes of the first instance of a series.
+    async fn series_attributes(&self, series_instance_uid: &str) -> Result<Attributes, FileError> {
+        if let Some(attributes) = self.attributes_cache.get(series_instance_uid) {
+            return Ok(attributes);
+        }
+        let (instances, _) = self
+            .find_instances(series_instance_uid, &QidoQuery::default())
+            .await?;
+        let first = instances
+            .first()
+            .ok_or_else(|| FileError::NotFound(self.instances_json_dir_for(series_instance_uid)))?;
+        let attributes = self
+            .instance_attributes(series_instance_uid, &first.sop_instance_uid)
+            .await
+            .map(Arc::new)?;
+        self.attributes_cache
+            .insert(series_instance_uid, Arc::clone(&attributes));
+        Ok(attributes)
+    }
+
+    /// Read the attributes of a DICOM instance from its file.
+    async fn instance_attributes(
+        &self,
+        series_instance_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<Map<String, Value>, FileError> {
+        let path = self
+            .get_instance_fslocation(series_instance_uid, sop_instance_uid)
+            .await?;
+        dicomfile_attributes(path).await
     }
 
     /// Count the number of DICOM instances in the specified series.
     async fn count_instances(&self, series_instance_uid: &str) -> Result<usize, std::io::Error> {
+        let indexed = self
+            .query_index(|index| index.count_instances(series_instance_uid))
+            .await;
+        if let Some(count) = indexed {
+            return Ok(count);
+        }
         let path = self.instances_json_dir_for(series_instance_uid);
         let read_dir = tokio::fs::read_dir(path).await?;
         let count = ReadDirStream::new(read_dir)
@@ -289,7 +1143,7 @@ impl PypxReader {
             })
     }
 
-    /// Find a file under `series_dir` called `????-{sop_instance_uid}.dcm.json`
+    /// Find a file under `series_dir` called `{InstanceNumber}-{sop_instance_uid}.dcm.json`
     async fn find_instance_meta_file(
         &self,
         series_dir: &Path,
@@ -306,9 +1160,9 @@ impl PypxReader {
                 let file_sop_instance_uid = path
                     .file_name()
                     .and_then(|file_name| file_name.to_str())
-                    .map(|file_name| {
-                        &file_name[("0000-".len())..(file_name.len() - ".dcm.json".len())]
-                    });
+                    .and_then(|file_name| file_name.strip_suffix(".dcm.json"))
+                    .and_then(|file_name| file_name.split_once('-'))
+                    .map(|(_, file_sop_instance_uid)| file_sop_instance_uid);
                 if Some(sop_instance_uid) == file_sop_instance_uid {
                     Some(path)
                 } else {
@@ -348,6 +1202,230 @@ impl PypxReader {
     }
 }
 
+/// A study and its patient, if `log/patientData` has a record of them.
+pub(crate) struct Study {
+    pub meta: StudyDataMeta<'static>,
+    pub patient: Option<Arc<PatientData<'static>>>,
+    /// Attributes aggregated from the series of the study, which are only computed when needed.
+    pub summary: Option<StudySummary>,
+}
+
+/// `patientData` by `PatientID`.
+type Patients = HashMap<String, Arc<PatientData<'static>>>;
+
+impl Study {
+    /// Produce the attributes of this study, including the attributes of its patient
+    /// (from `patients` if this study does not have them) and its [StudySummary].
+    fn to_dicomweb(&self, patients: &Patients) -> Value {
+        let mut study = study_meta_to_dicomweb(&self.meta);
+        let patient = self
+            .patient
+            .as_ref()
+            .or_else(|| patients.get(self.meta.PatientID.as_ref()));
+        if let Some(patient) = patient {
+            study = merge_dicomweb(study, patient_to_dicomweb(patient));
+        }
+        if let Some(summary) = &self.summary {
+            study = merge_dicomweb(study, study_summary_to_dicomweb(summary));
+        }
+        study
+    }
+}
+
+/// The study, series, or instance whose attributes are added to a result by `includefield`.
+enum Representative<'a> {
+    Study(&'a str),
+    Series(&'a str),
+    /// `SeriesInstanceUID` and `SOPInstanceUID`
+    Instance(&'a str, &'a str),
+}
+
+/// Attributes of the Patient, General Study and Patient Study modules, which are the
+/// attributes of a representative instance included in study results by `includefield=all`.
+/// Its other attributes describe only its series or the instance itself.
+const STUDY_LEVEL_TAGS: [Tag; 26] = [
+    tags::PATIENT_NAME,
+    tags::PATIENT_ID,
+    tags::ISSUER_OF_PATIENT_ID,
+    tags::PATIENT_BIRTH_DATE,
+    tags::PATIENT_BIRTH_TIME,
+    tags::PATIENT_SEX,
+    tags::OTHER_PATIENT_I_DS_SEQUENCE,
+    tags::ETHNIC_GROUP,
+    tags::PATIENT_COMMENTS,
+    tags::PATIENT_SPECIES_DESCRIPTION,
+    tags::PATIENT_IDENTITY_REMOVED,
+    tags::STUDY_INSTANCE_UID,
+    tags::STUDY_DATE,
+    tags::STUDY_TIME,
+    tags::REFERRING_PHYSICIAN_NAME,
+    tags::STUDY_ID,
+    tags::ACCESSION_NUMBER,
+    tags::ISSUER_OF_ACCESSION_NUMBER_SEQUENCE,
+    tags::STUDY_DESCRIPTION,
+    tags::PHYSICIANS_OF_RECORD,
+    tags::NAME_OF_PHYSICIANS_READING_STUDY,
+    tags::PROCEDURE_CODE_SEQUENCE,
+    tags::PATIENT_AGE,
+    tags::PATIENT_SIZE,
+    tags::PATIENT_WEIGHT,
+    tags::ADDITIONAL_PATIENT_HISTORY,
+];
+
+/// Attributes of the General Series, General Equipment and Frame of Reference modules,
+/// which are the attributes of a representative instance included in series results
+/// by `includefield=all`. Its other attributes describe only the instance itself.
+const SERIES_LEVEL_TAGS: [Tag; 26] = [
+    tags::MODALITY,
+    tags::SERIES_INSTANCE_UID,
+    tags::SERIES_NUMBER,
+    tags::LATERALITY,
+    tags::SERIES_DATE,
+    tags::SERIES_TIME,
+    tags::PERFORMING_PHYSICIAN_NAME,
+    tags::PROTOCOL_NAME,
+    tags::SERIES_DESCRIPTION,
+    tags::OPERATORS_NAME,
+    tags::BODY_PART_EXAMINED,
+    tags::PATIENT_POSITION,
+    tags::PERFORMED_PROCEDURE_STEP_ID,
+    tags::PERFORMED_PROCEDURE_STEP_START_DATE,
+    tags::PERFORMED_PROCEDURE_STEP_START_TIME,
+    tags::PERFORMED_PROCEDURE_STEP_DESCRIPTION,
+    tags::MANUFACTURER,
+    tags::INSTITUTION_NAME,
+    tags::INSTITUTION_ADDRESS,
+    tags::STATION_NAME,
+    tags::INSTITUTIONAL_DEPARTMENT_NAME,
+    tags::MANUFACTURER_MODEL_NAME,
+    tags::DEVICE_SERIAL_NUMBER,
+    tags::SOFTWARE_VERSIONS,
+    tags::FRAME_OF_REFERENCE_UID,
+    tags::POSITION_REFERENCE_INDICATOR,
+];
+
+/// Attributes of the header of a DICOM file.
+type Attributes = Arc<Map<String, Value>>;
+
+/// Maximum number of studies and series in an [AttributesCache].
+const ATTRIBUTES_CACHE_CAPACITY: usize = 4096;
+
+/// In-memory cache of the [Attributes] of the representative instance of studies and
+/// series, keyed by their UID. To keep it simple, the cache is emptied once it is full.
+#[derive(Default)]
+struct AttributesCache(Mutex<HashMap<String, Attributes>>);
+
+impl AttributesCache {
+    fn get(&self, uid: &str) -> Option<Attributes> {
+        self.0.lock().unwrap().get(uid).cloned()
+    }
+
+    fn insert(&self, uid: &str, attributes: Attributes) {
+        let mut cache = self.0.lock().unwrap();
+        if cache.len() >= ATTRIBUTES_CACHE_CAPACITY {
+            cache.clear();
+        }
+        cache.insert(uid.to_string(), attributes);
+    }
+}
+
+/// Attributes of [PatientData] which can be used for matching, but which are not
+/// in [StudyDataMeta].
+const PATIENT_ONLY_TAGS: [Tag; 4] = [
+    tags::PATIENT_NAME,
+    tags::PATIENT_BIRTH_DATE,
+    tags::PATIENT_SEX,
+    tags::PATIENT_AGE,
+];
+
+/// Attributes of [StudySummary] which can be used for matching.
+const STUDY_SUMMARY_TAGS: [Tag; 4] = [
+    tags::MODALITIES_IN_STUDY,
+    tags::ACCESSION_NUMBER,
+    tags::STUDY_TIME,
+    tags::REFERRING_PHYSICIAN_NAME,
+];
+
+/// Attributes of a study which are not in `studyData/{StudyInstanceUID}-meta.json`,
+/// but which are aggregated from `studyData/{StudyInstanceUID}-series`.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct StudySummary {
+    /// Distinct modalities of the series, separated by `\`.
+    pub modalities: String,
+    pub num_series: usize,
+    pub num_instances: usize,
+    pub accession_number: String,
+    pub study_time: String,
+    pub referring_physician_name: String,
+}
+
+impl StudySummary {
+    pub(crate) fn new(series: &[StudyDataSeriesMeta], num_instances: usize) -> Self {
+        let values_of = |keyword: &'static str| {
+            series
+                .iter()
+                .filter_map(move |s| s.DICOM.get(keyword))
+                .map(|v| v.value.trim())
+                .filter(|v| !v.is_empty())
+        };
+        let modalities: BTreeSet<_> = values_of("Modality").collect();
+        let first_value_of = |keyword| values_of(keyword).next().unwrap_or_default().to_string();
+        Self {
+            modalities: modalities.into_iter().collect::<Vec<_>>().join("\\"),
+            num_series: series.len(),
+            num_instances,
+            accession_number: first_value_of("AccessionNumber"),
+            study_time: first_value_of("StudyTime"),
+            referring_physician_name: first_value_of("ReferringPhysicianName"),
+        }
+    }
+}
+
+/// A DICOM instance, as described by the name of its JSON file
+/// `log/seriesData/{SeriesInstanceUID}-img/NNNN-{SOPInstanceUID}.dcm.json`,
+/// where `NNNN` is its `InstanceNumber`.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct InstanceFile {
+    pub instance_number: String,
+    pub sop_instance_uid: String,
+}
+
+impl InstanceFile {
+    pub(crate) fn from_path(path: &Path) -> Option<Self> {
+        let file_name = path.file_name()?.to_str()?;
+        let (instance_number, sop_instance_uid) =
+            file_name.strip_suffix(".dcm.json")?.split_once('-')?;
+        // remove zero-padding
+        let instance_number = instance_number
+            .parse::<i64>()
+            .map(|n| n.to_string())
+            .unwrap_or_else(|_| instance_number.to_string());
+        Some(Self {
+            instance_number,
+            sop_instance_uid: sop_instance_uid.to_string(),
+        })
+    }
+
+    /// Produce the attributes of this instance, including its `RetrieveURL`.
+    fn to_dicomweb(
+        &self,
+        study_instance_uid: &str,
+        series: &StudyDataSeriesMeta,
+        base_url: &str,
+    ) -> Value {
+        let retrieve_url = format!(
+            "{base_url}/studies/{study_instance_uid}/series/{}/instances/{}",
+            series.SeriesInstanceUID, self.sop_instance_uid
+        );
+        instance_to_dicomweb(
+            series,
+            &self.instance_number,
+            &self.sop_instance_uid,
+            &retrieve_url,
+        )
+    }
+}
+
 /// A wrapper to handle a bug in `rx-repack` which was fixed in version 1.0.3
 /// https://github.com/FNNDSC/pypx-listener/commit/b453fb375f180dbad6ebd9df27966b5ff0ac484e
 async fn read_study_meta_json(path: PathBuf) -> Result<StudyDataMeta<'static>, FileError> {
@@ -371,27 +1449,36 @@ async fn read_study_meta_json(path: PathBuf) -> Result<StudyDataMeta<'static>, F
     }
 }
 
-/// A wrapper for [study_matches] with lifetime annotations
+/// A wrapper for [QidoQuery::matches] with lifetime annotations
 /// so that it may be used with [StreamExt::filter_map].
 async fn study_matches_wrapper<'a>(
     study: StudyDataMeta<'a>,
-    query: &'a HashMap<String, String>,
+    query: &QidoQuery,
 ) -> Option<StudyDataMeta<'a>> {
-    if study_matches(&study, query) {
+    if query.matches(&study) {
         Some(study)
     } else {
         None
     }
 }
 
-/// Returns `true` if the given study matches the given query.
-fn study_matches(study: &StudyDataMeta, query: &HashMap<String, String>) -> bool {
-    if let Some(patient_id) = query.get(constants::PATIENT_ID) {
-        if patient_id != study.PatientID.as_ref() {
-            return false;
-        }
-    }
-    true
+/// Remove the [StudySummary] of studies, whose attributes are only in study results.
+fn without_summaries(studies: Vec<Study>) -> Vec<Study> {
+    studies
+        .into_iter()
+        .map(|study| Study {
+            summary: None,
+            ..study
+        })
+        .collect()
+}
+
+/// Get the `SeriesNumber` of a series as a number, for the purpose of sorting.
+fn series_number_of(series: &StudyDataSeriesMeta) -> Option<i64> {
+    series
+        .DICOM
+        .get("SeriesNumber")
+        .and_then(|v| v.value.trim().parse().ok())
 }
 
 fn flatten_notfound_error<T>(result: Result<T, FileError>) -> Result<Vec<T>, FileError> {
@@ -413,3 +1500,53 @@ async fn report_then_discard_error<T, E: std::error::Error>(result: Result<T, E>
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pypx::ValueAndLabel;
+    use rstest::*;
+    use std::borrow::Cow;
+
+    #[rstest]
+    fn test_study_summary() {
+        let series = [
+            example_series("1.2.3.1", &[("Modality", "MR"), ("StudyTime", "061609")]),
+            example_series(
+                "1.2.3.2",
+                &[("Modality", "SR"), ("AccessionNumber", "c89f3313")],
+            ),
+            example_series("1.2.3.3", &[("Modality", "MR"), ("AccessionNumber", "")]),
+        ];
+        let expected = StudySummary {
+            modalities: "MR\\SR".to_string(),
+            num_series: 3,
+            num_instances: 200,
+            accession_number: "c89f3313".to_string(),
+            study_time: "061609".to_string(),
+            referring_physician_name: "".to_string(),
+        };
+        assert_eq!(StudySummary::new(&series, 200), expected)
+    }
+
+    fn example_series(
+        series_instance_uid: &'static str,
+        dicom: &[(&'static str, &'static str)],
+    ) -> StudyDataSeriesMeta<'static> {
+        let dicom = dicom
+            .iter()
+            .map(|(label, value)| {
+                let value = ValueAndLabel {
+                    value: Cow::from(*value),
+                    label: Cow::from(*label),
+                };
+                (label.to_string(), value)
+            })
+            .collect();
+        StudyDataSeriesMeta {
+            SeriesInstanceUID: Cow::from(series_instance_uid),
+            SeriesBaseDir: Cow::from("/tmp/dicom/data"),
+            DICOM: dicom,
+        }
+    }
+}
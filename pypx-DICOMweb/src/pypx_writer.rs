@@ -0,0 +1,248 @@
+//! Writes DICOM files into a pypx-organized directory, the same way `rx-repack` does.
+//! JSON files are written by [pypx::PypxLogWriter].
+//!
+//! https://github.com/FNNDSC/pypx-listener
+
+use crate::dicom::{is_binary, is_uid};
+use crate::errors::StoreError;
+use crate::index::PypxIndex;
+use axum::body::Bytes;
+use dicom::core::header::Header;
+use dicom::core::{DataDictionary, DicomValue};
+use dicom::dictionary_std::{tags, StandardDataDictionary};
+use dicom::object::OpenFileOptions;
+use pypx::{DicomHeader, PypxLogWriter};
+use std::path::{Path, PathBuf};
+use tracing::{event, Level};
+
+/// A DICOM file which was uploaded, and its attributes.
+pub(crate) struct Upload {
+    bytes: Bytes,
+    /// Attributes of the DICOM file as strings, by keyword, e.g. `"PatientID"`.
+    /// Sequences and binary attributes are omitted.
+    dicom: DicomHeader,
+    pub sop_class_uid: String,
+    pub sop_instance_uid: String,
+    pub study_instance_uid: String,
+    pub series_instance_uid: String,
+}
+
+impl Upload {
+    /// Parse the header of an uploaded DICOM file.
+    pub fn parse(bytes: Bytes) -> Result<Self, StoreError> {
+        let dcm = OpenFileOptions::new()
+            .read_until(tags::PIXEL_DATA)
+            .from_reader(skip_preamble(&bytes))
+            .map_err(StoreError::Unreadable)?;
+        let mut dicom: DicomHeader = dcm
+            .iter()
+            .filter(|element| !is_binary(element.vr()))
+            .filter_map(|element| {
+                let keyword = StandardDataDictionary.by_tag(element.tag())?.alias;
+                if let DicomValue::Primitive(value) = element.value() {
+                    let value = value.to_str();
+                    let value = value.trim_end_matches([' ', '\0']);
+                    Some((keyword.to_string(), value.to_string()))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let meta = dcm.meta();
+        let sop_class_uid = dicom
+            .remove("SOPClassUID")
+            .unwrap_or_else(|| meta.media_storage_sop_class_uid().to_string());
+        let sop_instance_uid = dicom
+            .remove("SOPInstanceUID")
+            .unwrap_or_else(|| meta.media_storage_sop_instance_uid().to_string());
+        dicom.insert("SOPClassUID".to_string(), sop_class_uid.clone());
+        dicom.insert("SOPInstanceUID".to_string(), sop_instance_uid.clone());
+        let get_uid = |keyword: &'static str| {
+            let uid = dicom
+                .get(keyword)
+                .ok_or(StoreError::MissingAttribute(keyword))?;
+            if is_uid(uid) {
+                Ok(uid.to_string())
+            } else {
+                Err(StoreError::InvalidUid(keyword, uid.to_string()))
+            }
+        };
+        let study_instance_uid = get_uid("StudyInstanceUID")?;
+        let series_instance_uid = get_uid("SeriesInstanceUID")?;
+        let sop_instance_uid = get_uid("SOPInstanceUID")?;
+        Ok(Self {
+            bytes,
+            dicom,
+            sop_class_uid,
+            sop_instance_uid,
+            study_instance_uid,
+            series_instance_uid,
+        })
+    }
+
+    fn get(&self, keyword: &str) -> &str {
+        self.dicom
+            .get(keyword)
+            .map(|s| s.as_str())
+            .unwrap_or_default()
+    }
+
+    /// Path of the DICOM file relative to the data directory, e.g.
+    /// `{PatientID}-{PatientName}-{PatientBirthDate}/{StudyDescription}-{AccessionNumber}-{StudyDate}/{SeriesNumber}-{SeriesDescription}-{hash}`
+    fn series_dir(&self) -> PathBuf {
+        let patient_dir = format!(
+            "{}-{}-{}",
+            sanitize(self.get("PatientID")),
+            sanitize(self.get("PatientName")),
+            sanitize(self.get("PatientBirthDate"))
+        );
+        let study_dir = format!(
+            "{}-{}-{}",
+            sanitize(self.get("StudyDescription")),
+            sanitize(self.get("AccessionNumber")),
+            sanitize(self.get("StudyDate"))
+        );
+        let series_hash = format!("{:x}", md5::compute(&self.series_instance_uid));
+        let series_dir = format!(
+            "{}-{}-{}",
+            pad(self.get("SeriesNumber"), 5),
+            sanitize(self.get("SeriesDescription")),
+            &series_hash[..7]
+        );
+        [patient_dir, study_dir, series_dir].iter().collect()
+    }
+
+    /// File name of the DICOM file, `{InstanceNumber}-{SOPInstanceUID}.dcm`, where
+    /// `InstanceNumber` is zero-padded to 4 digits, or `0000` if it is missing or not
+    /// a number (same as `rx-repack`).
+    fn file_name(&self) -> String {
+        let instance_number = self
+            .get("InstanceNumber")
+            .trim()
+            .parse::<u32>()
+            .map(|n| format!("{n:04}"))
+            .unwrap_or_else(|_| "0000".to_string());
+        format!("{}-{}.dcm", instance_number, self.sop_instance_uid)
+    }
+}
+
+/// Writer of DICOM files and their JSON files into a pypx-organized directory.
+pub(crate) struct PypxWriter {
+    log: PypxLogWriter,
+    data_dir: PathBuf,
+
+    /// Path where the data directory is mounted for `rx-repack`. Paths written to
+    /// JSON files are relative to this path so that other pypx programs can read them.
+    repack_data_dir_mountpath: PathBuf,
+
+    /// Optional index of the log directory, which is updated with the instances which are written.
+    index: Option<PypxIndex>,
+}
+
+impl PypxWriter {
+    pub fn new(
+        log_dir: &Path,
+        data_dir: PathBuf,
+        repack_data_dir_mountpath: PathBuf,
+        index: Option<PypxIndex>,
+    ) -> Self {
+        Self {
+            log: PypxLogWriter::new(log_dir),
+            data_dir,
+            repack_data_dir_mountpath,
+            index,
+        }
+    }
+
+    /// Write a DICOM file and update the `studyData` and `seriesData` JSON files,
+    /// and the index if there is one. Failing to update the index is not an error,
+    /// because the index reads the study again from the log directory.
+    pub async fn write(&self, upload: &Upload) -> Result<(), StoreError> {
+        let series_dir = upload.series_dir();
+        let file_name = upload.file_name();
+        let dcm_dir = self.data_dir.join(&series_dir);
+        tokio::fs::create_dir_all(&dcm_dir)
+            .await
+            .map_err(|e| StoreError::IO(dcm_dir.clone(), e.kind()))?;
+        let dcm_path = dcm_dir.join(&file_name);
+        let (p, bytes) = (dcm_path.clone(), upload.bytes.clone());
+        tokio::task::spawn_blocking(move || pypx::write_atomically(&p, &bytes))
+            .await
+            .map_err(|error| StoreError::Runtime(dcm_path.clone(), error.into()))?
+            .map_err(|e| StoreError::IO(dcm_path.clone(), e.kind()))?;
+
+        let fs_location = self
+            .repack_data_dir_mountpath
+            .join(&series_dir)
+            .join(&file_name)
+            .to_string_lossy()
+            .to_string();
+        let log = self.log.clone();
+        let (header, location) = (upload.dicom.clone(), fs_location.clone());
+        tokio::task::spawn_blocking(move || log.write(&header, &location))
+            .await
+            .map_err(|error| StoreError::Runtime(dcm_path, error.into()))??;
+
+        if let Some(index) = &self.index {
+            if let Err(e) = index.insert_instance(&upload.dicom, &fs_location).await {
+                event!(Level::WARN, "Cannot add instance to the index: {:?}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Skip the 128-byte preamble of a DICOM file, if it has one.
+fn skip_preamble(bytes: &[u8]) -> &[u8] {
+    match bytes.get(128..132) {
+        Some(b"DICM") => &bytes[128..],
+        _ => bytes,
+    }
+}
+
+/// Replace characters which are troublesome in file names with `_`.
+fn sanitize(value: &str) -> String {
+    value
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Zero-pad a number, e.g. `SeriesNumber` and `InstanceNumber`.
+fn pad(value: &str, width: usize) -> String {
+    value
+        .trim()
+        .parse::<u32>()
+        .map(|n| format!("{n:0width$}"))
+        .unwrap_or_else(|_| sanitize(value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case("MR-Brain w/o Contrast", "MR-Brain_w_o_Contrast")]
+    #[case("NELSON^DAVID^ANON", "NELSON_DAVID_ANON")]
+    #[case("../etc", ".._etc")]
+    fn test_sanitize(#[case] value: &str, #[case] expected: &str) {
+        assert_eq!(sanitize(value), expected)
+    }
+
+    #[rstest]
+    #[case("5", 5, "00005")]
+    #[case(" 12 ", 4, "0012")]
+    #[case("", 4, "")]
+    #[case("x/y", 4, "x_y")]
+    fn test_pad(#[case] value: &str, #[case] width: usize, #[case] expected: &str) {
+        assert_eq!(pad(value, width), expected)
+    }
+}
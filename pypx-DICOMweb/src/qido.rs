@@ -0,0 +1,948 @@
+//! QIDO-RS query parameters and attribute matching.
+//!
+//! https://dicom.nema.org/medical/dicom/current/output/html/part18.html#sect_8.3.4
+//! https://dicom.nema.org/medical/dicom/current/output/html/part04.html#sect_C.2.2.2
+
+use crate::errors::InvalidQueryParameter;
+use crate::pypx_reader::{InstanceFile, Study, StudySummary};
+use crate::translate::tag2str;
+use dicom::core::dictionary::{DataDictionary, DataDictionaryEntry};
+use dicom::core::{Tag, VR};
+use dicom::dictionary_std::{tags, StandardDataDictionary};
+use pypx::{PatientData, StudyDataMeta, StudyDataSeriesMeta};
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Query parameters which are not attribute matching keys.
+const RESERVED_PARAMETERS: [&str; 4] = ["limit", "offset", "fuzzymatching", "includefield"];
+
+/// A parsed QIDO-RS query: a set of attribute matching keys.
+#[derive(Debug, Default)]
+pub(crate) struct QidoQuery {
+    keys: Vec<MatchingKey>,
+}
+
+/// Something which has DICOM attributes that can be matched against a [QidoQuery].
+pub(crate) trait QidoAttributes {
+    /// Get the (string) value of an attribute, or `None` if the attribute is unknown.
+    ///
+    /// Multiple values are separated by `\`, same as how they are encoded in DICOM.
+    fn attribute(&self, tag: Tag) -> Option<&str>;
+}
+
+/// A matching key of a [QidoQuery], for one attribute.
+#[derive(Debug)]
+pub(crate) struct MatchingKey {
+    /// Name of the query parameter, i.e. a keyword or tag.
+    name: String,
+    tag: Tag,
+    matcher: Matcher,
+    case_sensitive: bool,
+}
+
+/// The types of matching defined by PS3.4 C.2.2.2.
+#[derive(Debug, PartialEq)]
+enum Matcher {
+    /// Empty value or `*`, which matches anything.
+    Universal,
+    /// Exact match.
+    Single(String),
+    /// Value is any of the given values, e.g. a list of UIDs or modalities.
+    List(Vec<String>),
+    /// Value matches a pattern where `*` matches any sequence of characters
+    /// and `?` matches any single character.
+    Wildcard(String),
+    /// Value is between an inclusive lower and upper bound.
+    /// Used for DA, TM, and DT, including single value matching.
+    Range(Option<String>, Option<String>),
+}
+
+impl QidoQuery {
+    /// Parse the query parameters of a QIDO-RS request. Attributes may be specified
+    /// either by keyword (e.g. `PatientID`) or by tag (e.g. `00100020`).
+    pub fn parse(params: &HashMap<String, String>) -> Result<Self, InvalidQueryParameter> {
+        let fuzzy = params
+            .get("fuzzymatching")
+            .map(|v| v == "true")
+            .unwrap_or(false);
+        let keys = params
+            .iter()
+            .filter(|(name, _)| !RESERVED_PARAMETERS.contains(&name.as_str()))
+            .map(|(name, value)| MatchingKey::parse(name, value, fuzzy))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { keys })
+    }
+
+    /// The matching keys of this query, e.g. to translate them with [MatchingKey::to_sql].
+    pub fn keys(&self) -> &[MatchingKey] {
+        &self.keys
+    }
+
+    /// Get the value of a single value matching key.
+    pub fn single_value(&self, tag: Tag) -> Option<&str> {
+        self.keys
+            .iter()
+            .filter(|key| key.tag == tag)
+            .find_map(|key| match &key.matcher {
+                Matcher::Single(value) => Some(value.as_str()),
+                _ => None,
+            })
+    }
+
+    /// Reject matching keys for attributes other than `supported`, which would otherwise
+    /// be ignored by [QidoQuery::matches].
+    pub fn check_supported(&self, supported: &[Tag]) -> Result<(), InvalidQueryParameter> {
+        match self.keys.iter().find(|key| !supported.contains(&key.tag)) {
+            Some(key) => Err(InvalidQueryParameter(
+                key.name.to_string(),
+                "matching is not supported",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns `true` if this query has a matching key for the given attribute.
+    pub fn has_key(&self, tag: Tag) -> bool {
+        self.keys.iter().any(|key| key.tag == tag)
+    }
+
+    /// Returns `true` if the given data matches every key of this query.
+    ///
+    /// Keys for attributes which are unknown to `data` are ignored.
+    pub fn matches<T: QidoAttributes>(&self, data: &T) -> bool {
+        self.keys.iter().all(|key| {
+            data.attribute(key.tag)
+                .map(|value| key.matches(value))
+                .unwrap_or(true)
+        })
+    }
+}
+
+/// The `limit` and `offset` parameters of a QIDO-RS request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Pagination {
+    limit: usize,
+    offset: usize,
+}
+
+/// A page of (sorted) QIDO-RS results.
+#[derive(Debug)]
+pub(crate) struct Page<T> {
+    pub items: Vec<T>,
+    /// `true` if there are more results after this page.
+    pub truncated: bool,
+}
+
+impl Pagination {
+    /// Parse the `limit` and `offset` parameters of a QIDO-RS request.
+    pub fn parse(params: &HashMap<String, String>) -> Result<Self, InvalidQueryParameter> {
+        let parse_param = |name: &str, default: usize| {
+            params
+                .get(name)
+                .map(|value| value.parse())
+                .unwrap_or(Ok(default))
+                .map_err(|_| InvalidQueryParameter(name.to_string(), "must be a natural number"))
+        };
+        Ok(Self {
+            limit: parse_param("limit", usize::MAX)?,
+            offset: parse_param("offset", 0)?,
+        })
+    }
+
+    /// Number of items needed to select the page and to know whether it is truncated,
+    /// i.e. `offset + limit + 1`.
+    pub fn needed(&self) -> usize {
+        self.offset.saturating_add(self.limit).saturating_add(1)
+    }
+
+    /// Value of `LIMIT` for an SQL query of the page: one more row than the page, to know
+    /// whether it is truncated (see [Pagination::page_of]), or `-1` for no limit.
+    pub fn sql_limit(&self) -> i64 {
+        i64::try_from(self.limit.saturating_add(1)).unwrap_or(-1)
+    }
+
+    /// Value of `OFFSET` for an SQL query of the page.
+    pub fn sql_offset(&self) -> i64 {
+        i64::try_from(self.offset).unwrap_or(i64::MAX)
+    }
+
+    /// Make a page of the rows selected by [Pagination::sql_limit] and
+    /// [Pagination::sql_offset].
+    pub fn page_of<T>(&self, mut rows: Vec<T>) -> Page<T> {
+        let truncated = rows.len() > self.limit;
+        rows.truncate(self.limit);
+        Page {
+            items: rows,
+            truncated,
+        }
+    }
+
+    /// Select the page of `items` described by this [Pagination].
+    pub fn paginate<T>(&self, items: Vec<T>) -> Page<T> {
+        let truncated = items.len().saturating_sub(self.offset) > self.limit;
+        let items = items
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit)
+            .collect();
+        Page { items, truncated }
+    }
+}
+
+impl Default for Pagination {
+    /// Every result.
+    fn default() -> Self {
+        Self {
+            limit: usize::MAX,
+            offset: 0,
+        }
+    }
+}
+
+impl<T> Page<T> {
+    pub fn map<U, F: FnMut(T) -> U>(self, f: F) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            truncated: self.truncated,
+        }
+    }
+}
+
+/// The `includefield` parameter of a QIDO-RS request: attributes to return in addition
+/// to the ones which are returned by default.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) enum IncludeField {
+    #[default]
+    None,
+    /// `includefield=all`
+    All,
+    /// A comma-separated list of attributes, e.g. `includefield=00081030,StudyTime`
+    Tags(Vec<Tag>),
+}
+
+impl IncludeField {
+    /// Parse the `includefield` parameter of a QIDO-RS request.
+    pub fn parse(params: &HashMap<String, String>) -> Result<Self, InvalidQueryParameter> {
+        let value = match params.get("includefield").map(|v| v.trim()) {
+            None | Some("") => return Ok(Self::None),
+            Some(value) => value,
+        };
+        if value == "all" {
+            return Ok(Self::All);
+        }
+        value
+            .split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                StandardDataDictionary
+                    .by_expr(name)
+                    .map(|entry| entry.tag())
+                    .ok_or_else(|| InvalidQueryParameter(name.to_string(), "unknown attribute"))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self::Tags)
+    }
+
+    pub fn is_none(&self) -> bool {
+        matches!(self, Self::None)
+    }
+
+    /// Add the requested attributes of `header` which `dcm` does not already have.
+    /// If every attribute is requested, only the attributes of `header` in `allowed`
+    /// are added, or all of them if `allowed` is `None`.
+    pub fn include(
+        &self,
+        dcm: &mut Map<String, Value>,
+        header: &Map<String, Value>,
+        allowed: Option<&[Tag]>,
+    ) {
+        match self {
+            Self::None => (),
+            Self::All => {
+                let allowed: Option<Vec<_>> =
+                    allowed.map(|tags| tags.iter().map(|tag| tag2str(*tag)).collect());
+                for (key, value) in header {
+                    let is_allowed = allowed.as_ref().map_or(true, |a| a.contains(key));
+                    if is_allowed && !dcm.contains_key(key) {
+                        dcm.insert(key.to_string(), value.clone());
+                    }
+                }
+            }
+            Self::Tags(tags) => {
+                for key in tags.iter().map(|tag| tag2str(*tag)) {
+                    if let (false, Some(value)) = (dcm.contains_key(&key), header.get(&key)) {
+                        dcm.insert(key, value.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl MatchingKey {
+    fn parse(name: &str, value: &str, fuzzy: bool) -> Result<Self, InvalidQueryParameter> {
+        let (tag, vr) = StandardDataDictionary
+            .by_expr(name)
+            .map(|entry| (entry.tag(), entry.vr()))
+            .ok_or_else(|| InvalidQueryParameter(name.to_string(), "unknown attribute"))?;
+        if vr == VR::SQ {
+            return Err(InvalidQueryParameter(
+                name.to_string(),
+                "sequence matching is not supported",
+            ));
+        }
+        let case_sensitive = !(fuzzy || vr == VR::PN);
+        let value = if case_sensitive {
+            Cow::Borrowed(value)
+        } else {
+            Cow::Owned(value.to_lowercase())
+        };
+        let matcher = Matcher::parse(&value, vr)
+            .ok_or_else(|| InvalidQueryParameter(name.to_string(), "invalid value"))?;
+        Ok(Self {
+            name: name.to_string(),
+            tag,
+            matcher,
+            case_sensitive,
+        })
+    }
+
+    pub fn tag(&self) -> Tag {
+        self.tag
+    }
+
+    /// Translate this key to an SQL condition on the value of `column`, and its
+    /// parameters. Returns `None` for universal matching.
+    ///
+    /// Same as [MatchingKey::matches], except that `column` has a single value, and
+    /// that case-insensitive matching only ignores the case of ASCII letters.
+    pub fn to_sql(&self, column: &str) -> Option<(String, Vec<String>)> {
+        let column = if self.case_sensitive {
+            format!("rtrim({column})")
+        } else {
+            format!("lower(rtrim({column}))")
+        };
+        let condition = match &self.matcher {
+            Matcher::Universal => return None,
+            Matcher::Single(value) => (format!("{column} = ?"), vec![value.to_string()]),
+            Matcher::List(values) => {
+                let params = vec!["?"; values.len()].join(", ");
+                (format!("{column} IN ({params})"), values.clone())
+            }
+            // `*` and `?` are the same in GLOB patterns, but `[` is special
+            Matcher::Wildcard(pattern) => (
+                format!("{column} GLOB ?"),
+                vec![pattern.replace('[', "[[]")],
+            ),
+            Matcher::Range(start, end) => {
+                // same as normalize_datetime
+                let value = format!("replace(replace(trim({column}), '.', ''), ':', '')");
+                let mut conditions = vec![format!("{value} <> ''")];
+                let mut params = Vec::new();
+                if let Some(start) = start {
+                    conditions.push(format!("{value} >= ?"));
+                    params.push(start.to_string());
+                }
+                if let Some(end) = end {
+                    conditions.push(format!(
+                        "({value} <= ? OR substr({value}, 1, length(?)) = ?)"
+                    ));
+                    params.extend([end.to_string(), end.to_string(), end.to_string()]);
+                }
+                (conditions.join(" AND "), params)
+            }
+        };
+        Some(condition)
+    }
+
+    /// Returns `true` if any of the `\`-separated values matches.
+    fn matches(&self, value: &str) -> bool {
+        if self.matcher == Matcher::Universal {
+            return true;
+        }
+        if self.case_sensitive {
+            value.split('\\').any(|v| self.matcher.matches(v))
+        } else {
+            value
+                .to_lowercase()
+                .split('\\')
+                .any(|v| self.matcher.matches(v))
+        }
+    }
+}
+
+impl Matcher {
+    fn parse(value: &str, vr: VR) -> Option<Self> {
+        let value = if matches!(vr, VR::UI | VR::DA | VR::TM | VR::DT) {
+            value.trim()
+        } else {
+            value
+        };
+        if value.is_empty() || value == "*" {
+            return Some(Self::Universal);
+        }
+        let matcher = match vr {
+            VR::UI => {
+                let uids: Vec<_> = value
+                    .split([',', '\\'])
+                    .map(|uid| uid.trim().to_string())
+                    .collect();
+                if uids.iter().any(|uid| uid.is_empty()) {
+                    return None;
+                }
+                if uids.len() == 1 {
+                    Self::Single(value.to_string())
+                } else {
+                    Self::List(uids)
+                }
+            }
+            // e.g. ModalitiesInStudy=CT\MR
+            VR::CS if value.contains(['\\', ',']) => {
+                let values = value
+                    .split([',', '\\'])
+                    .map(|v| v.trim().to_string())
+                    .filter(|v| !v.is_empty())
+                    .collect();
+                Self::List(values)
+            }
+            VR::DA | VR::TM | VR::DT => {
+                if let Some((start, end)) = split_range(value, vr) {
+                    if start.is_empty() && end.is_empty() {
+                        return None;
+                    }
+                    let start = Some(normalize_datetime(start)).filter(|s| !s.is_empty());
+                    let end = Some(normalize_datetime(end)).filter(|s| !s.is_empty());
+                    Self::Range(start, end)
+                } else {
+                    let value = normalize_datetime(value);
+                    Self::Range(Some(value.clone()), Some(value))
+                }
+            }
+            _ => {
+                if value.contains(['*', '?']) {
+                    Self::Wildcard(value.to_string())
+                } else {
+                    Self::Single(value.to_string())
+                }
+            }
+        };
+        Some(matcher)
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Self::Universal => true,
+            Self::Single(expected) => expected == value.trim_end(),
+            Self::List(values) => values.iter().any(|v| v == value.trim_end()),
+            Self::Wildcard(pattern) => wildcard_matches(pattern, value.trim_end()),
+            Self::Range(start, end) => {
+                let value = normalize_datetime(value);
+                if value.is_empty() {
+                    return false;
+                }
+                start
+                    .as_ref()
+                    .map(|s| s.as_str() <= value.as_str())
+                    .unwrap_or(true)
+                    && end
+                        .as_ref()
+                        .map(|e| value.as_str() <= e.as_str() || value.starts_with(e.as_str()))
+                        .unwrap_or(true)
+            }
+        }
+    }
+}
+
+/// Split a range of dates or times on the `-` which separates its bounds.
+///
+/// A `DT` value may end with a negative UTC offset (e.g. `20230101120000-0500`), whose
+/// `-` is not a separator: it follows a date and time (at least `YYYYMMDDHH`) and is
+/// followed by 4 digits, then by the end of the value or by the separator.
+fn split_range(value: &str, vr: VR) -> Option<(&str, &str)> {
+    if vr != VR::DT {
+        return value.split_once('-');
+    }
+    let is_utc_offset = |i: usize| {
+        let before = value[..i].rsplit('-').next().unwrap_or_default();
+        let after = &value.as_bytes()[i + 1..];
+        before.len() >= 10
+            && after.len() >= 4
+            && after[..4].iter().all(u8::is_ascii_digit)
+            && after.get(4).map_or(true, |c| *c == b'-')
+    };
+    value
+        .match_indices('-')
+        .map(|(i, _)| i)
+        .find(|i| !is_utc_offset(*i))
+        .map(|i| (&value[..i], &value[i + 1..]))
+}
+
+/// Remove the separators of the ACR-NEMA date and time formats
+/// (`YYYY.MM.DD` and `HH:MM:SS`) so that values can be compared as strings.
+fn normalize_datetime(value: &str) -> String {
+    value.trim().replace(['.', ':'], "")
+}
+
+/// Match a value against a pattern containing `*` and `?` wildcards.
+fn wildcard_matches(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+    // position in pattern and value after the most recent `*`
+    let mut backtrack: Option<(usize, usize)> = None;
+    let (mut p, mut v) = (0, 0);
+    while v < value.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == value[v]) {
+            p += 1;
+            v += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p + 1, v));
+            p += 1;
+        } else if let Some((bp, bv)) = backtrack {
+            p = bp;
+            v = bv + 1;
+            backtrack = Some((bp, bv + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Attributes of a [PatientData] which can be matched.
+pub(crate) const PATIENT_MATCHING_TAGS: [Tag; 5] = [
+    tags::PATIENT_ID,
+    tags::PATIENT_NAME,
+    tags::PATIENT_BIRTH_DATE,
+    tags::PATIENT_SEX,
+    tags::PATIENT_AGE,
+];
+
+/// Attributes of a [Study] which can be matched: those of its [StudyDataMeta],
+/// [PatientData] and [StudySummary].
+pub(crate) const STUDY_MATCHING_TAGS: [Tag; 13] = [
+    tags::STUDY_DESCRIPTION,
+    tags::STUDY_DATE,
+    tags::STUDY_INSTANCE_UID,
+    tags::PERFORMED_STATION_AE_TITLE,
+    tags::PATIENT_ID,
+    tags::PATIENT_NAME,
+    tags::PATIENT_BIRTH_DATE,
+    tags::PATIENT_SEX,
+    tags::PATIENT_AGE,
+    tags::MODALITIES_IN_STUDY,
+    tags::ACCESSION_NUMBER,
+    tags::STUDY_TIME,
+    tags::REFERRING_PHYSICIAN_NAME,
+];
+
+/// Attributes of a [StudyDataSeriesMeta] which can be matched.
+pub(crate) const SERIES_MATCHING_TAGS: [Tag; 5] = [
+    tags::MODALITY,
+    tags::SERIES_INSTANCE_UID,
+    tags::SERIES_NUMBER,
+    tags::SERIES_DESCRIPTION,
+    tags::SERIES_DATE,
+];
+
+/// Attributes of an [InstanceFile] which can be matched.
+pub(crate) const INSTANCE_MATCHING_TAGS: [Tag; 2] = [tags::SOP_INSTANCE_UID, tags::INSTANCE_NUMBER];
+
+impl QidoAttributes for StudyDataMeta<'_> {
+    fn attribute(&self, tag: Tag) -> Option<&str> {
+        match tag {
+            tags::PATIENT_ID => Some(&self.PatientID),
+            tags::STUDY_DESCRIPTION => Some(&self.StudyDescription),
+            tags::STUDY_DATE => Some(&self.StudyDate),
+            tags::STUDY_INSTANCE_UID => Some(&self.StudyInstanceUID),
+            tags::PERFORMED_STATION_AE_TITLE => Some(&self.PerformedStationAETitle),
+            _ => None,
+        }
+    }
+}
+
+impl QidoAttributes for PatientData<'_> {
+    fn attribute(&self, tag: Tag) -> Option<&str> {
+        match tag {
+            tags::PATIENT_ID => Some(&self.PatientID),
+            tags::PATIENT_NAME => Some(&self.PatientName),
+            tags::PATIENT_BIRTH_DATE => Some(&self.PatientBirthDate),
+            tags::PATIENT_SEX => Some(&self.PatientSex),
+            tags::PATIENT_AGE => Some(&self.PatientAge),
+            _ => None,
+        }
+    }
+}
+
+impl QidoAttributes for Study {
+    fn attribute(&self, tag: Tag) -> Option<&str> {
+        self.meta
+            .attribute(tag)
+            .or_else(|| self.patient.as_ref()?.attribute(tag))
+            .or_else(|| self.summary.as_ref()?.attribute(tag))
+    }
+}
+
+impl QidoAttributes for StudySummary {
+    fn attribute(&self, tag: Tag) -> Option<&str> {
+        match tag {
+            tags::MODALITIES_IN_STUDY => Some(&self.modalities),
+            tags::ACCESSION_NUMBER => Some(&self.accession_number),
+            tags::STUDY_TIME => Some(&self.study_time),
+            tags::REFERRING_PHYSICIAN_NAME => Some(&self.referring_physician_name),
+            _ => None,
+        }
+    }
+}
+
+impl QidoAttributes for StudyDataSeriesMeta<'_> {
+    fn attribute(&self, tag: Tag) -> Option<&str> {
+        match tag {
+            tags::SERIES_INSTANCE_UID => return Some(&self.SeriesInstanceUID),
+            // pypx records the instance-level attributes of one instance of the series,
+            // which do not describe the series as a whole.
+            tags::SOP_INSTANCE_UID | tags::INSTANCE_NUMBER => return None,
+            _ => (),
+        }
+        let keyword = StandardDataDictionary.by_tag(tag)?.alias();
+        self.DICOM.get(keyword).map(|v| v.value.as_ref())
+    }
+}
+
+impl QidoAttributes for InstanceFile {
+    fn attribute(&self, tag: Tag) -> Option<&str> {
+        match tag {
+            tags::SOP_INSTANCE_UID => Some(&self.sop_instance_uid),
+            tags::INSTANCE_NUMBER => Some(&self.instance_number),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pypx::ValueAndLabel;
+    use rstest::*;
+    use serde_json::json;
+    use std::borrow::Cow;
+    use std::sync::Arc;
+
+    #[rstest]
+    #[case(&[], true)]
+    #[case(&[("PatientID", "1449c1d")], true)]
+    #[case(&[("00100020", "1449c1d")], true)]
+    #[case(&[("PatientID", "1449")], false)]
+    #[case(&[("PatientID", "1449*")], true)]
+    #[case(&[("PatientID", "1449?1d")], true)]
+    #[case(&[("PatientID", "*")], true)]
+    #[case(&[("PatientID", "")], true)]
+    #[case(&[("StudyDate", "20130101-20131231")], true)]
+    #[case(&[("StudyDate", "20130309-")], false)]
+    #[case(&[("StudyDate", "-20130308")], true)]
+    #[case(&[("StudyDate", "20130308")], true)]
+    #[case(&[("StudyDate", "2013.03.08")], true)]
+    #[case(&[("StudyDate", "20140101-20141231")], false)]
+    #[case(&[("StudyDescription", "*brain*")], false)]
+    #[case(&[("StudyDescription", "*Brain*")], true)]
+    #[case(&[("StudyDescription", "*brain*"), ("fuzzymatching", "true")], true)]
+    #[case(&[("StudyInstanceUID", "1.2.3,1.2.840.113845.11.1000000001785349915.20130308061609.6346698")], true)]
+    #[case(&[("StudyInstanceUID", "1.2.3\\4.5.6")], false)]
+    #[case(&[("PatientID", "1449c1d"), ("StudyDate", "2014")], false)]
+    #[case(&[("limit", "1"), ("offset", "2")], true)]
+    fn test_study_matches(
+        example_study_meta: StudyDataMeta,
+        #[case] params: &[(&str, &str)],
+        #[case] expected: bool,
+    ) {
+        let query = QidoQuery::parse(&to_params(params)).unwrap();
+        assert_eq!(query.matches(&example_study_meta), expected);
+    }
+
+    #[rstest]
+    #[case("NotAKeyword", "hello")]
+    #[case("ReferencedStudySequence", "1.2.3")]
+    #[case("StudyDate", "-")]
+    #[case("StudyInstanceUID", "1.2.3,")]
+    fn test_invalid_query(#[case] name: &str, #[case] value: &str) {
+        assert!(QidoQuery::parse(&to_params(&[(name, value)])).is_err())
+    }
+
+    #[rstest]
+    #[case("Doe^John", "DOE^*", true)]
+    #[case("Doe^John", "doe^john", true)]
+    #[case("Doe^John", "Smith*", false)]
+    fn test_person_name_case_insensitive(
+        #[case] value: &str,
+        #[case] query: &str,
+        #[case] expected: bool,
+    ) {
+        let key = MatchingKey::parse("PatientName", query, false).unwrap();
+        assert_eq!(key.matches(value), expected)
+    }
+
+    #[rstest]
+    #[case("MR\\CT", "CT", true)]
+    #[case("MR\\CT", "US", false)]
+    #[case("MR", "CT\\MR", true)]
+    #[case("MR\\CT", "US,CT", true)]
+    #[case("SR", "CT\\MR", false)]
+    fn test_multiple_values(#[case] value: &str, #[case] query: &str, #[case] expected: bool) {
+        let key = MatchingKey::parse("ModalitiesInStudy", query, false).unwrap();
+        assert_eq!(key.matches(value), expected)
+    }
+
+    #[rstest]
+    #[case("*", "", true)]
+    #[case("a*c", "abbbc", true)]
+    #[case("a*c", "abbbd", false)]
+    #[case("*b*b*", "abcbd", true)]
+    #[case("a?c", "abc", true)]
+    #[case("a?c", "ac", false)]
+    #[case("abc", "abcd", false)]
+    fn test_wildcard_matches(#[case] pattern: &str, #[case] value: &str, #[case] expected: bool) {
+        assert_eq!(wildcard_matches(pattern, value), expected)
+    }
+
+    #[rstest]
+    #[case("1200-1300", "123000", true)]
+    #[case("1200-1300", "130059", true)]
+    #[case("1200-1300", "130100", false)]
+    #[case("12:00-", "115959.999", false)]
+    fn test_time_range(#[case] query: &str, #[case] value: &str, #[case] expected: bool) {
+        let key = MatchingKey::parse("StudyTime", query, false).unwrap();
+        assert_eq!(key.matches(value), expected)
+    }
+
+    #[rstest]
+    #[case("20230101-20230102", Some(("20230101", "20230102")))]
+    #[case("20230101120000-0500-20230102", Some(("20230101120000-0500", "20230102")))]
+    #[case("-20230102120000-0500", Some(("", "20230102120000-0500")))]
+    #[case("20230101120000+0100-", Some(("20230101120000+0100", "")))]
+    #[case("2023-2024", Some(("2023", "2024")))]
+    #[case("20230101120000-0500", None)]
+    fn test_datetime_range(#[case] query: &str, #[case] expected: Option<(&str, &str)>) {
+        assert_eq!(split_range(query, VR::DT), expected);
+        let key = MatchingKey::parse("AcquisitionDateTime", query, false).unwrap();
+        assert!(key.matches("20230101120000-0500"));
+    }
+
+    #[rstest]
+    #[case(&[("PatientName", "nelson*")], Some(true))]
+    #[case(&[("PatientName", "DOE^JOHN")], Some(false))]
+    #[case(&[("PatientSex", "M"), ("PatientID", "1449c1d")], Some(true))]
+    #[case(&[("PatientBirthDate", "-20000101")], Some(false))]
+    #[case(&[("PatientName", "DOE^JOHN")], None)]
+    fn test_study_with_patient_matches(
+        example_study_meta: StudyDataMeta<'static>,
+        #[case] params: &[(&str, &str)],
+        #[case] with_patient: Option<bool>,
+    ) {
+        let patient = with_patient.map(|_| {
+            Arc::new(PatientData {
+                PatientID: Cow::from("1449c1d"),
+                PatientName: Cow::from("NELSON^DAVID^ANON"),
+                PatientAge: Cow::from("011Y"),
+                PatientSex: Cow::from("M"),
+                PatientBirthDate: Cow::from("20090101"),
+                StudyList: vec![example_study_meta.StudyInstanceUID.clone()],
+            })
+        });
+        let study = Study {
+            meta: example_study_meta,
+            patient,
+            summary: None,
+        };
+        let query = QidoQuery::parse(&to_params(params)).unwrap();
+        // without patientData, patient attributes are unknown, hence ignored
+        assert_eq!(query.matches(&study), with_patient.unwrap_or(true));
+    }
+
+    #[rstest]
+    #[case(&[("AccessionNumber", "c89f3313")], true)]
+    #[case(&[("AccessionNumber", "12345")], false)]
+    #[case(&[("ModalitiesInStudy", "MR"), ("StudyTime", "0600-0700")], true)]
+    #[case(&[("ModalitiesInStudy", "CT")], false)]
+    fn test_study_with_summary_matches(
+        example_study_meta: StudyDataMeta<'static>,
+        #[case] params: &[(&str, &str)],
+        #[case] expected: bool,
+    ) {
+        let summary = StudySummary {
+            modalities: "MR\\SR".to_string(),
+            accession_number: "c89f3313".to_string(),
+            study_time: "061609".to_string(),
+            ..Default::default()
+        };
+        let study = Study {
+            meta: example_study_meta,
+            patient: None,
+            summary: Some(summary),
+        };
+        let query = QidoQuery::parse(&to_params(params)).unwrap();
+        assert_eq!(query.matches(&study), expected);
+    }
+
+    #[rstest]
+    #[case("AccessionNumber", true)]
+    #[case("00080050", true)]
+    #[case("PatientAge", true)]
+    #[case("SeriesDescription", false)]
+    #[case("SOPInstanceUID", false)]
+    fn test_check_supported(#[case] name: &str, #[case] expected: bool) {
+        let query = QidoQuery::parse(&to_params(&[(name, "*")])).unwrap();
+        assert_eq!(
+            query.check_supported(&STUDY_MATCHING_TAGS).is_ok(),
+            expected
+        )
+    }
+
+    #[rstest]
+    #[case(&[("Modality", "MR")], true)]
+    #[case(&[("Modality", "CT")], false)]
+    #[case(&[("SeriesNumber", "5")], true)]
+    #[case(&[("SeriesDescription", "SAG*MPRAGE")], true)]
+    #[case(&[("SeriesDescription", "AX*")], false)]
+    #[case(&[("SeriesInstanceUID", "1.2.3\\1.3.12.2.1107.5.2.43.166047")], true)]
+    #[case(&[("SeriesInstanceUID", "1.2.3")], false)]
+    fn test_series_matches(
+        example_series_meta: StudyDataSeriesMeta,
+        #[case] params: &[(&str, &str)],
+        #[case] expected: bool,
+    ) {
+        let query = QidoQuery::parse(&to_params(params)).unwrap();
+        assert_eq!(query.matches(&example_series_meta), expected);
+    }
+
+    #[rstest]
+    #[case(&[], 10, (0..10).collect(), false)]
+    #[case(&[("limit", "3")], 10, vec![0, 1, 2], true)]
+    #[case(&[("limit", "3"), ("offset", "6")], 10, vec![6, 7, 8], true)]
+    #[case(&[("limit", "3"), ("offset", "7")], 10, vec![7, 8, 9], false)]
+    #[case(&[("limit", "3"), ("offset", "9")], 10, vec![9], false)]
+    #[case(&[("offset", "20")], 10, vec![], false)]
+    #[case(&[("limit", "0")], 10, vec![], true)]
+    fn test_paginate(
+        #[case] params: &[(&str, &str)],
+        #[case] count: usize,
+        #[case] expected: Vec<usize>,
+        #[case] truncated: bool,
+    ) {
+        let pagination = Pagination::parse(&to_params(params)).unwrap();
+        let page = pagination.paginate((0..count).collect());
+        assert_eq!(page.items, expected);
+        assert_eq!(page.truncated, truncated);
+        // the first `needed` items are enough to select the same page
+        let needed = pagination.needed().min(count);
+        let page = pagination.paginate((0..needed).collect());
+        assert_eq!(page.items, expected);
+        assert_eq!(page.truncated, truncated);
+    }
+
+    #[rstest]
+    #[case("limit", "-1")]
+    #[case("offset", "one")]
+    fn test_invalid_pagination(#[case] name: &str, #[case] value: &str) {
+        assert!(Pagination::parse(&to_params(&[(name, value)])).is_err())
+    }
+
+    #[rstest]
+    #[case(&[], IncludeField::None)]
+    #[case(&[("includefield", "")], IncludeField::None)]
+    #[case(&[("includefield", "all")], IncludeField::All)]
+    #[case(
+        &[("includefield", "00081030, StudyTime")],
+        IncludeField::Tags(vec![tags::STUDY_DESCRIPTION, tags::STUDY_TIME])
+    )]
+    fn test_parse_includefield(#[case] params: &[(&str, &str)], #[case] expected: IncludeField) {
+        assert_eq!(IncludeField::parse(&to_params(params)).unwrap(), expected)
+    }
+
+    #[rstest]
+    fn test_invalid_includefield() {
+        let params = to_params(&[("includefield", "StudyTime,NotAKeyword")]);
+        assert!(IncludeField::parse(&params).is_err())
+    }
+
+    #[rstest]
+    #[case(IncludeField::None, None, &["00100020"])]
+    #[case(IncludeField::All, None, &["00100020", "00080030", "00081030", "0020000D"])]
+    #[case(
+        IncludeField::All,
+        Some(&[tags::STUDY_TIME, tags::STUDY_DESCRIPTION][..]),
+        &["00100020", "00080030", "00081030"]
+    )]
+    #[case(
+        IncludeField::Tags(vec![tags::STUDY_TIME, tags::PATIENT_AGE]),
+        Some(&[tags::STUDY_DESCRIPTION][..]),
+        &["00100020", "00080030"]
+    )]
+    fn test_include(
+        #[case] include: IncludeField,
+        #[case] allowed: Option<&[Tag]>,
+        #[case] expected: &[&str],
+    ) {
+        let mut dcm = json!({"00100020": {"vr": "LO", "Value": ["1449c1d"]}});
+        let header = json!({
+            "00100020": {"vr": "LO", "Value": ["other"]},
+            "00080030": {"vr": "TM", "Value": ["061609"]},
+            "00081030": {"vr": "LO", "Value": ["MR-Brain w/o Contrast"]},
+            "0020000D": {"vr": "UI", "Value": ["1.2.3"]}
+        });
+        include.include(
+            dcm.as_object_mut().unwrap(),
+            header.as_object().unwrap(),
+            allowed,
+        );
+        let mut keys: Vec<_> = dcm.as_object().unwrap().keys().collect();
+        let mut expected: Vec<_> = expected.to_vec();
+        keys.sort();
+        expected.sort();
+        assert_eq!(keys, expected);
+        assert_eq!(dcm["00100020"]["Value"][0], "1449c1d")
+    }
+
+    fn to_params(params: &[(&str, &str)]) -> HashMap<String, String> {
+        params
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[fixture]
+    fn example_series_meta() -> StudyDataSeriesMeta<'static> {
+        let dicom = [
+            ("Modality", "MR"),
+            ("SeriesNumber", "5"),
+            ("SeriesDescription", "SAG T1 MPRAGE"),
+        ]
+        .into_iter()
+        .map(|(label, value)| {
+            let value = ValueAndLabel {
+                value: Cow::from(value),
+                label: Cow::from(label),
+            };
+            (label.to_string(), value)
+        })
+        .collect();
+        StudyDataSeriesMeta {
+            SeriesInstanceUID: Cow::from("1.3.12.2.1107.5.2.43.166047"),
+            SeriesBaseDir: Cow::from("/tmp/dicom/data/00005-SAG_T1_MPRAGE-4f9e281"),
+            DICOM: dicom,
+        }
+    }
+
+    #[fixture]
+    fn example_study_meta() -> StudyDataMeta<'static> {
+        StudyDataMeta {
+            PatientID: Cow::from("1449c1d"),
+            StudyDescription: Cow::from("MR-Brain w/o Contrast"),
+            StudyDate: Cow::from("20130308"),
+            StudyInstanceUID: Cow::from(
+                "1.2.840.113845.11.1000000001785349915.20130308061609.6346698",
+            ),
+            PerformedStationAETitle: Cow::from("Not defined"),
+        }
+    }
+}
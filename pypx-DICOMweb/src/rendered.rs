@@ -0,0 +1,590 @@
+//! WADO-RS rendered resources: DICOM images as consumer image formats.
+//!
+//! https://dicom.nema.org/medical/dicom/current/output/html/part18.html#sect_8.3.5.1
+
+use crate::dicom::convert_error;
+use crate::errors::{FileError, InvalidQueryParameter};
+use dicom::core::{PrimitiveValue, Tag, VR};
+use dicom::dictionary_std::tags;
+use dicom::object::mem::InMemElement;
+use dicom::object::DefaultDicomObject;
+use dicom::pixeldata::{ConvertOptions, PixelDecoder, VoiLutOption, WindowLevel};
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImage, GenericImageView, ImageOutputFormat, Rgba};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+/// Image formats supported by the rendered resources.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum RenderedMediaType {
+    Jpeg,
+    Png,
+    Gif,
+}
+
+impl RenderedMediaType {
+    /// Choose the first supported media type of the acceptable media types given
+    /// by the client. If the client did not specify any, the default is JPEG.
+    pub fn negotiate<'a>(mut accept: impl Iterator<Item = &'a str>) -> Option<Self> {
+        let mut any = false;
+        let found = accept.find_map(|media_type| {
+            any = true;
+            match media_type {
+                "image/jpeg" | "image/*" | "*/*" => Some(Self::Jpeg),
+                "image/png" => Some(Self::Png),
+                "image/gif" => Some(Self::Gif),
+                _ => None,
+            }
+        });
+        if any {
+            found
+        } else {
+            Some(Self::Jpeg)
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Gif => "image/gif",
+        }
+    }
+}
+
+/// Query parameters of a rendered resource request.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RenderOptions {
+    pub media_type: RenderedMediaType,
+    window: Option<Window>,
+    viewport: Option<Viewport>,
+    quality: u8,
+    annotation: Vec<Annotation>,
+}
+
+/// The `window` query parameter: VOI LUT transformation overriding the
+/// window specified in the DICOM file.
+#[derive(Clone, Debug, PartialEq)]
+struct Window {
+    center: f64,
+    width: f64,
+    function: &'static str,
+}
+
+/// The `viewport` query parameter: the size of the output image, and
+/// optionally the region of the source image to render.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Viewport {
+    width: u32,
+    height: u32,
+    source_x: u32,
+    source_y: u32,
+    source_width: Option<i64>,
+    source_height: Option<i64>,
+}
+
+/// Values of the `annotation` query parameter.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Annotation {
+    Patient,
+    Technique,
+}
+
+const DEFAULT_QUALITY: u8 = 90;
+
+/// Default width and height of thumbnails.
+const THUMBNAIL_SIZE: u32 = 128;
+
+impl RenderOptions {
+    pub fn parse(
+        params: &HashMap<String, String>,
+        media_type: RenderedMediaType,
+    ) -> Result<Self, InvalidQueryParameter> {
+        let window = params.get("window").map(|s| parse_window(s)).transpose()?;
+        let viewport = params
+            .get("viewport")
+            .map(|s| parse_viewport(s))
+            .transpose()?;
+        let quality = params
+            .get("quality")
+            .map(|s| {
+                s.parse()
+                    .ok()
+                    .filter(|q| (1..=100).contains(q))
+                    .ok_or_else(|| {
+                        InvalidQueryParameter(
+                            "quality".to_string(),
+                            "must be an integer from 1 to 100",
+                        )
+                    })
+            })
+            .transpose()?
+            .unwrap_or(DEFAULT_QUALITY);
+        let annotation = params
+            .get("annotation")
+            .map(|s| {
+                s.split(',')
+                    .map(|value| match value {
+                        "patient" => Ok(Annotation::Patient),
+                        "technique" => Ok(Annotation::Technique),
+                        _ => Err(InvalidQueryParameter(
+                            "annotation".to_string(),
+                            "must be \"patient\" or \"technique\"",
+                        )),
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+        Ok(Self {
+            media_type,
+            window,
+            viewport,
+            quality,
+            annotation,
+        })
+    }
+
+    /// Parse the query parameters of a thumbnail request. Only `viewport` is supported,
+    /// and by default thumbnails fit within [THUMBNAIL_SIZE].
+    pub fn parse_thumbnail(
+        params: &HashMap<String, String>,
+        media_type: RenderedMediaType,
+    ) -> Result<Self, InvalidQueryParameter> {
+        let viewport = params
+            .get("viewport")
+            .map(|s| parse_viewport(s))
+            .transpose()?
+            .unwrap_or(Viewport {
+                width: THUMBNAIL_SIZE,
+                height: THUMBNAIL_SIZE,
+                source_x: 0,
+                source_y: 0,
+                source_width: None,
+                source_height: None,
+            });
+        Ok(Self {
+            media_type,
+            window: None,
+            viewport: Some(viewport),
+            quality: DEFAULT_QUALITY,
+            annotation: vec![],
+        })
+    }
+
+    /// A file name for caching a thumbnail of an instance rendered with these options.
+    pub fn thumbnail_file_name(&self, sop_instance_uid: &str) -> String {
+        let size = self
+            .viewport
+            .map(|v| {
+                let region = [v.source_width, v.source_height]
+                    .into_iter()
+                    .map(|n| n.map(|n| n.to_string()).unwrap_or_default())
+                    .collect::<Vec<_>>()
+                    .join("x");
+                format!(
+                    "{}x{}_{}_{}_{region}",
+                    v.width, v.height, v.source_x, v.source_y
+                )
+            })
+            .unwrap_or_default();
+        let extension = match self.media_type {
+            RenderedMediaType::Jpeg => "jpg",
+            RenderedMediaType::Png => "png",
+            RenderedMediaType::Gif => "gif",
+        };
+        format!("{sop_instance_uid}_{size}.{extension}")
+    }
+}
+
+fn parse_window(s: &str) -> Result<Window, InvalidQueryParameter> {
+    let invalid = || {
+        InvalidQueryParameter(
+            "window".to_string(),
+            "must be center,width,function where function is one of \
+            linear, linear-exact, sigmoid",
+        )
+    };
+    let mut values = s.split(',');
+    let center = values
+        .next()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(invalid)?;
+    let width = values
+        .next()
+        .and_then(|v| v.parse().ok())
+        .filter(|width: &f64| *width > 0.0)
+        .ok_or_else(invalid)?;
+    let function = match values.next() {
+        None | Some("linear") => "LINEAR",
+        Some("linear-exact") => "LINEAR_EXACT",
+        Some("sigmoid") => "SIGMOID",
+        Some(_) => return Err(invalid()),
+    };
+    if values.next().is_some() {
+        return Err(invalid());
+    }
+    Ok(Window {
+        center,
+        width,
+        function,
+    })
+}
+
+fn parse_viewport(s: &str) -> Result<Viewport, InvalidQueryParameter> {
+    let invalid = || InvalidQueryParameter("viewport".to_string(), "must be vw,vh[,sx,sy,sw,sh]");
+    let values: Vec<_> = s.split(',').collect();
+    if values.len() != 2 && values.len() != 6 {
+        return Err(invalid());
+    }
+    let positive = |v: &str| v.parse().ok().filter(|n: &u32| *n > 0).ok_or_else(invalid);
+    // sx, sy, sw, sh may each be omitted, e.g. "512,512,,,,"
+    let optional = |i: usize| {
+        values
+            .get(i)
+            .filter(|v| !v.is_empty())
+            .map(|v| v.parse::<i64>().map_err(|_| invalid()))
+            .transpose()
+    };
+    let offset = |i: usize| {
+        optional(i)?
+            .map(|n| u32::try_from(n).map_err(|_| invalid()))
+            .transpose()
+            .map(Option::unwrap_or_default)
+    };
+    Ok(Viewport {
+        width: positive(values[0])?,
+        height: positive(values[1])?,
+        source_x: offset(2)?,
+        source_y: offset(3)?,
+        source_width: optional(4)?.filter(|n| *n != 0),
+        source_height: optional(5)?.filter(|n| *n != 0),
+    })
+}
+
+/// Render a frame (zero-indexed) of a DICOM file. Returns `None` if the frame does not exist.
+pub(crate) async fn render_frame(
+    path: PathBuf,
+    frame: u32,
+    options: RenderOptions,
+) -> Result<Option<Vec<u8>>, FileError> {
+    let p = path.to_path_buf();
+    tokio::task::spawn_blocking(move || render_frame_sync(&p, frame, &options))
+        .await
+        .map_err(|error| FileError::Runtime(path, error.into()))?
+}
+
+fn render_frame_sync(
+    path: &Path,
+    frame: u32,
+    options: &RenderOptions,
+) -> Result<Option<Vec<u8>>, FileError> {
+    let mut dcm = dicom::object::open_file(path).map_err(|error| convert_error(path, error))?;
+    let number_of_frames = dcm
+        .element(tags::NUMBER_OF_FRAMES)
+        .ok()
+        .and_then(|e| e.to_int::<u32>().ok())
+        .unwrap_or(1);
+    if frame >= number_of_frames {
+        return Ok(None);
+    }
+    let voi_lut = if let Some(window) = &options.window {
+        // the VOI LUT function is read from the DICOM object by dicom-pixeldata
+        dcm.put(InMemElement::new(
+            tags::VOILUT_FUNCTION,
+            VR::CS,
+            PrimitiveValue::from(window.function),
+        ));
+        VoiLutOption::Custom(WindowLevel {
+            center: window.center,
+            width: window.width,
+        })
+    } else {
+        VoiLutOption::Default
+    };
+    let malformed = |reason: &str, error: Box<dyn std::error::Error + Send + Sync>| {
+        FileError::Malformed(path.to_path_buf(), reason.to_string(), Some(error))
+    };
+    let pixel_data = dcm
+        .decode_pixel_data()
+        .map_err(|error| malformed("Could not decode pixel data", error.into()))?;
+    let convert_options = ConvertOptions::new().with_voi_lut(voi_lut).force_8bit();
+    let mut image = pixel_data
+        .to_dynamic_image_with_options(frame, &convert_options)
+        .map_err(|error| malformed("Could not convert pixel data to image", error.into()))?;
+    if let Some(viewport) = &options.viewport {
+        image = apply_viewport(image, viewport);
+    }
+    for annotation in &options.annotation {
+        let lines = annotation_text(&dcm, *annotation, frame, number_of_frames, options);
+        draw_annotation(&mut image, &lines, *annotation);
+    }
+    let format = match options.media_type {
+        RenderedMediaType::Jpeg => ImageOutputFormat::Jpeg(options.quality),
+        RenderedMediaType::Png => ImageOutputFormat::Png,
+        RenderedMediaType::Gif => ImageOutputFormat::Gif,
+    };
+    let mut buf = Cursor::new(Vec::new());
+    image
+        .write_to(&mut buf, format)
+        .map_err(|error| malformed("Could not encode image", error.into()))?;
+    Ok(Some(buf.into_inner()))
+}
+
+/// Crop the image to the source region of the viewport, flipping it if the source
+/// width or height is negative, then scale it to fit the viewport (preserving aspect ratio).
+fn apply_viewport(image: DynamicImage, viewport: &Viewport) -> DynamicImage {
+    let sx = viewport.source_x.min(image.width().saturating_sub(1));
+    let sy = viewport.source_y.min(image.height().saturating_sub(1));
+    let sw = viewport.source_width.unwrap_or(image.width() as i64);
+    let sh = viewport.source_height.unwrap_or(image.height() as i64);
+    let crop_width = (sw.unsigned_abs() as u32).min(image.width() - sx);
+    let crop_height = (sh.unsigned_abs() as u32).min(image.height() - sy);
+    let mut image = image.crop_imm(sx, sy, crop_width, crop_height);
+    if sw < 0 {
+        image = image.fliph();
+    }
+    if sh < 0 {
+        image = image.flipv();
+    }
+    image.resize(viewport.width, viewport.height, FilterType::Triangle)
+}
+
+/// Lines of text to burn into the image for an annotation.
+fn annotation_text(
+    dcm: &DefaultDicomObject,
+    annotation: Annotation,
+    frame: u32,
+    number_of_frames: u32,
+    options: &RenderOptions,
+) -> Vec<String> {
+    let get = |tag: Tag| {
+        dcm.element(tag)
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .map(|s| s.trim().replace('^', " "))
+            .filter(|s| !s.is_empty())
+    };
+    let lines = match annotation {
+        Annotation::Patient => vec![
+            get(tags::PATIENT_NAME),
+            get(tags::PATIENT_ID),
+            [get(tags::PATIENT_BIRTH_DATE), get(tags::PATIENT_SEX)]
+                .into_iter()
+                .flatten()
+                .reduce(|a, b| format!("{a} {b}")),
+        ],
+        Annotation::Technique => vec![
+            [get(tags::MODALITY), get(tags::SERIES_DESCRIPTION)]
+                .into_iter()
+                .flatten()
+                .reduce(|a, b| format!("{a} {b}")),
+            options
+                .window
+                .as_ref()
+                .map(|w| format!("C {} W {}", w.center, w.width))
+                .or_else(|| {
+                    get(tags::WINDOW_CENTER)
+                        .zip(get(tags::WINDOW_WIDTH))
+                        .map(|(c, w)| format!("C {c} W {w}"))
+                }),
+            Some(format!("FRAME {}/{}", frame + 1, number_of_frames))
+                .filter(|_| number_of_frames > 1),
+        ],
+    };
+    lines.into_iter().flatten().collect()
+}
+
+/// Draw lines of text at the top left (patient) or bottom left (technique) of the image.
+fn draw_annotation(image: &mut DynamicImage, lines: &[String], annotation: Annotation) {
+    let scale = (image.height() / 256).max(1);
+    let line_height = (GLYPH_HEIGHT + 2) * scale;
+    let margin = 2 * scale;
+    let first_line_y = match annotation {
+        Annotation::Patient => margin,
+        Annotation::Technique => image
+            .height()
+            .saturating_sub(margin + line_height * lines.len() as u32),
+    };
+    for (i, line) in lines.iter().enumerate() {
+        let y = first_line_y + line_height * i as u32;
+        // black shadow to make text readable on bright pixels
+        draw_text(
+            image,
+            line,
+            margin + scale,
+            y + scale,
+            scale,
+            Rgba([0, 0, 0, 255]),
+        );
+        draw_text(image, line, margin, y, scale, Rgba([255, 255, 255, 255]));
+    }
+}
+
+fn draw_text(image: &mut DynamicImage, text: &str, x: u32, y: u32, scale: u32, color: Rgba<u8>) {
+    let (width, height) = image.dimensions();
+    for (i, c) in text.chars().enumerate() {
+        let glyph_x = x + i as u32 * (GLYPH_WIDTH + 1) * scale;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - col)) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let px = glyph_x + col * scale + dx;
+                        let py = y + row as u32 * scale + dy;
+                        if px < width && py < height {
+                            image.put_pixel(px, py, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+
+/// A 5x7 bitmap font. Each row is the lower 5 bits of a byte, most significant bit on the left.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT as usize] {
+    match c.to_ascii_uppercase() {
+        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case(vec![], Some(RenderedMediaType::Jpeg))]
+    #[case(vec!["image/png"], Some(RenderedMediaType::Png))]
+    #[case(vec!["application/pdf", "image/gif"], Some(RenderedMediaType::Gif))]
+    #[case(vec!["*/*"], Some(RenderedMediaType::Jpeg))]
+    #[case(vec!["application/dicom"], None)]
+    fn test_negotiate(#[case] accept: Vec<&str>, #[case] expected: Option<RenderedMediaType>) {
+        assert_eq!(RenderedMediaType::negotiate(accept.into_iter()), expected)
+    }
+
+    #[rstest]
+    #[case("40,400,linear", Some((40.0, 400.0, "LINEAR")))]
+    #[case("-600,1500.5,sigmoid", Some((-600.0, 1500.5, "SIGMOID")))]
+    #[case("40,400", Some((40.0, 400.0, "LINEAR")))]
+    #[case("40,0,linear", None)]
+    #[case("40,400,cubic", None)]
+    #[case("40", None)]
+    fn test_parse_window(#[case] s: &str, #[case] expected: Option<(f64, f64, &str)>) {
+        let actual = parse_window(s)
+            .ok()
+            .map(|w| (w.center, w.width, w.function));
+        assert_eq!(actual, expected)
+    }
+
+    type ViewportValues = (u32, u32, u32, u32, Option<i64>, Option<i64>);
+
+    #[rstest]
+    #[case("256,128", Some((256, 128, 0, 0, None, None)))]
+    #[case("256,256,10,20,-100,50", Some((256, 256, 10, 20, Some(-100), Some(50))))]
+    #[case("256,256,,,,", Some((256, 256, 0, 0, None, None)))]
+    #[case("0,256", None)]
+    #[case("256,256,-1,0,10,10", None)]
+    #[case("256", None)]
+    fn test_parse_viewport(#[case] s: &str, #[case] expected: Option<ViewportValues>) {
+        let actual = parse_viewport(s).ok().map(|v| {
+            (
+                v.width,
+                v.height,
+                v.source_x,
+                v.source_y,
+                v.source_width,
+                v.source_height,
+            )
+        });
+        assert_eq!(actual, expected)
+    }
+
+    #[rstest]
+    #[case(&[], "1.2.3_128x128_0_0_x.jpg")]
+    #[case(&[("viewport", "64,32")], "1.2.3_64x32_0_0_x.jpg")]
+    #[case(&[("viewport", "64,32,1,2,-3,4"), ("window", "1,2")], "1.2.3_64x32_1_2_-3x4.jpg")]
+    fn test_thumbnail_file_name(#[case] params: &[(&str, &str)], #[case] expected: &str) {
+        let params = params
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let options = RenderOptions::parse_thumbnail(&params, RenderedMediaType::Jpeg).unwrap();
+        assert_eq!(options.thumbnail_file_name("1.2.3"), expected)
+    }
+
+    #[rstest]
+    #[case("quality", "abc")]
+    #[case("quality", "101")]
+    #[case("window", "40")]
+    #[case("viewport", "256")]
+    #[case("annotation", "patient,age")]
+    fn test_invalid_parameter_name(#[case] name: &str, #[case] value: &str) {
+        let params = [(name.to_string(), value.to_string())]
+            .into_iter()
+            .collect();
+        let InvalidQueryParameter(actual, _) =
+            RenderOptions::parse(&params, RenderedMediaType::Jpeg).unwrap_err();
+        assert_eq!(actual, name)
+    }
+
+    #[rstest]
+    fn test_apply_viewport() {
+        let image = DynamicImage::new_luma8(100, 50);
+        let viewport = parse_viewport("20,20,50,0,-40,50").unwrap();
+        let actual = apply_viewport(image, &viewport);
+        assert_eq!(actual.dimensions(), (16, 20))
+    }
+}
@@ -1,67 +1,513 @@
-//! Router definition for DICOMweb (QIDO, WADO-rs) routes.
+//! Router definition for DICOMweb (QIDO, WADO-rs, STOW-rs) routes.
 
-use crate::constants::MULTIPART_BOUNDARY;
-use crate::dicom::encode_frame;
-use crate::errors::{FileError, ReadDirError};
+use crate::archive::zip_body;
+use crate::constants::{
+    APPLICATION_DICOM, APPLICATION_DICOM_JSON, APPLICATION_OCTET_STREAM, APPLICATION_ZIP,
+    WARNING_ADDITIONAL_RESULTS,
+};
+use crate::errors::{FileError, InvalidQueryParameter, ReadDirError};
+use crate::frames::{retrieve_frames, AcceptedType, Frames};
+use crate::multipart::{MultipartRelated, Part};
 use crate::pypx_reader::PypxReader;
-use axum::extract::{Path, Query, State};
-use axum::http::{header, StatusCode};
+use crate::pypx_writer::PypxWriter;
+use crate::qido::{
+    IncludeField, Page, Pagination, QidoQuery, INSTANCE_MATCHING_TAGS, PATIENT_MATCHING_TAGS,
+    SERIES_MATCHING_TAGS, STUDY_MATCHING_TAGS,
+};
+use crate::rendered::{render_frame, RenderOptions, RenderedMediaType};
+use crate::stow::{parse_boundary, store_instances, StoreResults};
+use axum::async_trait;
+use axum::extract::rejection::HostRejection;
+use axum::extract::{BodyStream, FromRef, FromRequestParts, Host, OriginalUri, Path, Query, State};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::{routing::get, Json, Router};
+use futures::StreamExt;
 use serde_json::Value;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tracing::{event, Level};
 
-pub fn get_router(pypx: PypxReader) -> Router {
+/// State shared by the handlers of [get_router].
+#[derive(Clone)]
+struct AppState {
+    reader: Arc<PypxReader>,
+    writer: Arc<PypxWriter>,
+}
+
+impl FromRef<AppState> for Arc<PypxReader> {
+    fn from_ref(state: &AppState) -> Self {
+        Arc::clone(&state.reader)
+    }
+}
+
+impl FromRef<AppState> for Arc<PypxWriter> {
+    fn from_ref(state: &AppState) -> Self {
+        Arc::clone(&state.writer)
+    }
+}
+
+pub fn get_router(pypx: PypxReader, writer: PypxWriter) -> Router {
     Router::new()
-        .route("/studies", get(get_studies))
+        .route("/studies", get(get_studies).post(store_study_instances))
+        .route(
+            "/studies/:study_instance_uid",
+            get(retrieve_study).post(store_study_instances),
+        )
+        .route(
+            "/studies/:study_instance_uid/metadata",
+            get(get_study_metadata),
+        )
         .route("/studies/:study_instance_uid/series", get(get_series))
+        .route(
+            "/studies/:study_instance_uid/series/:series_instance_uid",
+            get(retrieve_series),
+        )
+        .route(
+            "/studies/:study_instance_uid/series/:series_instance_uid/instances",
+            get(get_instances),
+        )
+        .route("/series", get(get_all_series))
+        .route("/patients", get(get_patients))
+        .route("/instances", get(get_all_instances))
         .route(
             "/studies/:study_instance_uid/series/:series_instance_uid/metadata",
             get(get_series_metadata),
         )
+        .route(
+            "/studies/:study_instance_uid/series/:series_instance_uid/instances/:sop_instance_uid/metadata",
+            get(get_instance_metadata),
+        )
+        .route(
+            "/studies/:study_instance_uid/series/:series_instance_uid/instances/:sop_instance_uid/bulkdata/*tag_path",
+            get(get_bulkdata),
+        )
+        .route(
+            "/studies/:study_instance_uid/series/:series_instance_uid/instances/:sop_instance_uid",
+            get(retrieve_instance),
+        )
         .route("/studies/:study_instance_uid/series/:series_instance_uid/instances/:sop_instance_uid/frames/:frame", get(get_frame))
-        .with_state(Arc::new(pypx))
+        .route(
+            "/studies/:study_instance_uid/series/:series_instance_uid/rendered",
+            get(get_rendered_series),
+        )
+        .route(
+            "/studies/:study_instance_uid/series/:series_instance_uid/instances/:sop_instance_uid/rendered",
+            get(get_rendered_instance),
+        )
+        .route(
+            "/studies/:study_instance_uid/series/:series_instance_uid/instances/:sop_instance_uid/frames/:frame/rendered",
+            get(get_rendered_frame),
+        )
+        .route(
+            "/studies/:study_instance_uid/thumbnail",
+            get(get_study_thumbnail),
+        )
+        .route(
+            "/studies/:study_instance_uid/series/:series_instance_uid/thumbnail",
+            get(get_series_thumbnail),
+        )
+        .route(
+            "/studies/:study_instance_uid/series/:series_instance_uid/instances/:sop_instance_uid/thumbnail",
+            get(get_instance_thumbnail),
+        )
+        .with_state(AppState {
+            reader: Arc::new(pypx),
+            writer: Arc::new(writer),
+        })
 }
 
 async fn get_studies(
     State(pypx): State<Arc<PypxReader>>,
     Query(params): Query<HashMap<String, String>>,
-) -> Result<Json<Vec<Value>>, QueryError> {
-    let limit = if let Some(l) = params.get("limit") {
-        l.parse().or(Err(QueryError::BadRequest(
-            "limit must be a natural number",
-        )))?
-    } else {
-        usize::MAX
-    };
-    pypx.query_studies(&params, limit)
+) -> Result<Page<Value>, QueryError> {
+    let query = QidoQuery::parse(&params)?;
+    query.check_supported(&STUDY_MATCHING_TAGS)?;
+    let pagination = Pagination::parse(&params)?;
+    let include = IncludeField::parse(&params)?;
+    pypx.query_studies(&query, pagination, &include)
+        .await
+        .map_err(|e| e.into())
+}
+
+/// Non-standard QIDO-like query for patients, which includes the number of studies
+/// of each patient (`NumberOfPatientRelatedStudies`).
+async fn get_patients(
+    State(pypx): State<Arc<PypxReader>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Page<Value>, QueryError> {
+    let query = QidoQuery::parse(&params)?;
+    query.check_supported(&PATIENT_MATCHING_TAGS)?;
+    let pagination = Pagination::parse(&params)?;
+    pypx.query_patients(&query, pagination)
         .await
-        .map(Json)
         .map_err(|e| e.into())
 }
 
 async fn get_series(
     State(pypx): State<Arc<PypxReader>>,
     Path(study_instance_uid): Path<String>,
-) -> Result<Json<Vec<Value>>, ReadDirError> {
-    pypx.get_series(&study_instance_uid).await.map(Json)
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Page<Value>, QueryError> {
+    let query = QidoQuery::parse(&params)?;
+    query.check_supported(&SERIES_MATCHING_TAGS)?;
+    let pagination = Pagination::parse(&params)?;
+    let include = IncludeField::parse(&params)?;
+    pypx.query_series(&study_instance_uid, &query, pagination, &include)
+        .await
+        .map_err(|e| e.into())
+}
+
+async fn get_all_series(
+    State(pypx): State<Arc<PypxReader>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Page<Value>, QueryError> {
+    let query = QidoQuery::parse(&params)?;
+    query.check_supported(&[STUDY_MATCHING_TAGS.as_slice(), &SERIES_MATCHING_TAGS].concat())?;
+    let pagination = Pagination::parse(&params)?;
+    let include = IncludeField::parse(&params)?;
+    pypx.query_all_series(&query, pagination, &include)
+        .await
+        .map_err(|e| e.into())
+}
+
+async fn get_all_instances(
+    State(pypx): State<Arc<PypxReader>>,
+    Query(params): Query<HashMap<String, String>>,
+    BaseUrl(base_url): BaseUrl,
+) -> Result<Page<Value>, QueryError> {
+    let query = QidoQuery::parse(&params)?;
+    query.check_supported(
+        &[
+            STUDY_MATCHING_TAGS.as_slice(),
+            &SERIES_MATCHING_TAGS,
+            &INSTANCE_MATCHING_TAGS,
+        ]
+        .concat(),
+    )?;
+    let pagination = Pagination::parse(&params)?;
+    let include = IncludeField::parse(&params)?;
+    pypx.query_all_instances(&query, pagination, &include, &base_url)
+        .await
+        .map_err(|e| e.into())
+}
+
+async fn get_instances(
+    State(pypx): State<Arc<PypxReader>>,
+    Path((study_instance_uid, series_instance_uid)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    BaseUrl(base_url): BaseUrl,
+) -> Result<Page<Value>, QueryError> {
+    let query = QidoQuery::parse(&params)?;
+    query.check_supported(&[SERIES_MATCHING_TAGS.as_slice(), &INSTANCE_MATCHING_TAGS].concat())?;
+    let pagination = Pagination::parse(&params)?;
+    let include = IncludeField::parse(&params)?;
+    pypx.query_instances(
+        &study_instance_uid,
+        &series_instance_uid,
+        &query,
+        pagination,
+        &include,
+        &base_url,
+    )
+    .await
+    .map_err(|e| e.into())
+}
+
+async fn get_study_metadata(
+    State(pypx): State<Arc<PypxReader>>,
+    Path(study_instance_uid): Path<String>,
+    BaseUrl(base_url): BaseUrl,
+) -> Result<Json<Vec<Value>>, FileError> {
+    pypx.get_study_dicomweb_metadata(&study_instance_uid, &base_url)
+        .await
+        .map(Json)
 }
 
 async fn get_series_metadata(
     State(pypx): State<Arc<PypxReader>>,
     Path((study_instance_uid, series_instance_uid)): Path<(String, String)>,
+    BaseUrl(base_url): BaseUrl,
 ) -> Result<Json<Vec<Value>>, FileError> {
     // TODO caching headers
-    pypx.get_series_dicomweb_metadata(&study_instance_uid, &series_instance_uid)
+    pypx.get_series_dicomweb_metadata(&study_instance_uid, &series_instance_uid, &base_url)
         .await
         .map(Json)
 }
 
-/// Respond with a frame of a DICOM file encoded as JPEG wrapped with multipart.
+async fn get_instance_metadata(
+    State(pypx): State<Arc<PypxReader>>,
+    Path((_study_instance_uid, series_instance_uid, sop_instance_uid)): Path<(
+        String,
+        String,
+        String,
+    )>,
+    BaseUrl(base_url): BaseUrl,
+) -> Result<Json<Vec<Value>>, FileError> {
+    pypx.get_instance_dicomweb_metadata(&series_instance_uid, &sop_instance_uid, &base_url)
+        .await
+        .map(|dcm| Json(vec![dcm]))
+}
+
+/// Respond with the raw bytes of an attribute referenced by a `BulkDataURI`.
+async fn get_bulkdata(
+    State(pypx): State<Arc<PypxReader>>,
+    Path((_study_instance_uid, series_instance_uid, sop_instance_uid, tag_path)): Path<(
+        String,
+        String,
+        String,
+        String,
+    )>,
+) -> Result<Response, FileError> {
+    let bulkdata = pypx
+        .get_instance_bulkdata(&series_instance_uid, &sop_instance_uid, tag_path)
+        .await?;
+    let response = if let Some(bytes) = bulkdata {
+        let part = Part::from_bytes(APPLICATION_OCTET_STREAM, bytes);
+        MultipartRelated::new(APPLICATION_OCTET_STREAM).response_once(part)
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    };
+    Ok(response)
+}
+
+/// Respond with all the DICOM files of a study wrapped with multipart.
+async fn retrieve_study(
+    State(pypx): State<Arc<PypxReader>>,
+    Path(study_instance_uid): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, FileError> {
+    let files = pypx.get_study_dcm_files(&study_instance_uid).await?;
+    if accepts_zip(&headers, &params) {
+        Ok(zip_response(files, &study_instance_uid))
+    } else {
+        Ok(multipart_dicom_response(files))
+    }
+}
+
+/// Respond with all the DICOM files of a series wrapped with multipart.
+async fn retrieve_series(
+    State(pypx): State<Arc<PypxReader>>,
+    Path((study_instance_uid, series_instance_uid)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, FileError> {
+    let files = pypx
+        .get_series_dcm_files(&study_instance_uid, &series_instance_uid)
+        .await?;
+    if accepts_zip(&headers, &params) {
+        Ok(zip_response(files, &series_instance_uid))
+    } else {
+        Ok(multipart_dicom_response(files))
+    }
+}
+
+/// Store uploaded DICOM instances (STOW-RS). When a study is given in the path,
+/// instances of other studies are rejected.
+async fn store_study_instances(
+    State(writer): State<Arc<PypxWriter>>,
+    study_instance_uid: Option<Path<String>>,
+    BaseUrl(base_url): BaseUrl,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> Response {
+    let boundary = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_boundary);
+    let boundary = if let Some(boundary) = boundary {
+        boundary
+    } else {
+        let message = "Request body must be multipart/related; type=\"application/dicom\"";
+        return (StatusCode::UNSUPPORTED_MEDIA_TYPE, message).into_response();
+    };
+    let study_instance_uid = study_instance_uid.map(|Path(study)| study);
+    match store_instances(
+        &writer,
+        body,
+        boundary,
+        study_instance_uid.as_deref(),
+        &base_url,
+    )
+    .await
+    {
+        Ok(results) => {
+            let retrieve_url = study_instance_uid
+                .as_ref()
+                .map(|study| format!("{base_url}/studies/{study}"));
+            store_response(results, retrieve_url.as_deref())
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    }
+}
+
+/// STOW-RS response, a DICOM JSON object with `ReferencedSOPSequence` and `FailedSOPSequence`.
+/// https://dicom.nema.org/medical/dicom/current/output/html/part18.html#sect_10.5.3
+fn store_response(results: StoreResults, retrieve_url: Option<&str>) -> Response {
+    let headers = [(header::CONTENT_TYPE, APPLICATION_DICOM_JSON)];
+    let body = results.to_dicomweb(retrieve_url).to_string();
+    (results.status(), headers, body).into_response()
+}
+
+/// Returns `true` if the client asked for `application/zip`.
+fn accepts_zip(headers: &HeaderMap, params: &HashMap<String, String>) -> bool {
+    accepted_media_types(headers, params).any(|media_type| media_type == APPLICATION_ZIP)
+}
+
+/// The media types which the client asked for, either by the `Accept` header
+/// or by the `accept` query parameter (which is handy for download links).
+fn accepted_media_types<'a>(
+    headers: &'a HeaderMap,
+    params: &'a HashMap<String, String>,
+) -> impl Iterator<Item = &'a str> {
+    let header_values = headers
+        .get_all(header::ACCEPT)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok());
+    params
+        .get("accept")
+        .map(|s| s.as_str())
+        .into_iter()
+        .chain(header_values)
+        .flat_map(|value| value.split(','))
+        .filter_map(|media_range| media_range.split(';').next())
+        .map(|media_type| media_type.trim())
+}
+
+/// Stream DICOM files as a ZIP archive download.
+fn zip_response(files: Vec<PathBuf>, name: &str) -> Response {
+    let headers = [
+        (header::CONTENT_TYPE, APPLICATION_ZIP.to_string()),
+        (
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{name}.zip\""),
+        ),
+    ];
+    (headers, zip_body(files)).into_response()
+}
+
+/// Stream DICOM files as a `multipart/related; type="application/dicom"` response.
+/// Files are read as the response is sent, since a study can be several gigabytes.
+fn multipart_dicom_response(files: Vec<PathBuf>) -> Response {
+    let parts = futures::stream::iter(files).map(|path| Part::from_path(APPLICATION_DICOM, path));
+    MultipartRelated::new(APPLICATION_DICOM).response(parts)
+}
+
+/// Respond with a DICOM file wrapped with multipart.
+async fn retrieve_instance(
+    State(pypx): State<Arc<PypxReader>>,
+    Path((_study_instance_uid, series_instance_uid, sop_instance_uid)): Path<(
+        String,
+        String,
+        String,
+    )>,
+) -> Result<Response, FileError> {
+    let path = pypx
+        .get_instance_fslocation(&series_instance_uid, &sop_instance_uid)
+        .await?;
+    let file = tokio::fs::File::open(&path)
+        .await
+        .map_err(|e| FileError::from_io_error(path, e))?;
+    let part = Part::from_file(APPLICATION_DICOM, file);
+    Ok(MultipartRelated::new(APPLICATION_DICOM).response_once(part))
+}
+
+/// Respond with frames of a DICOM file wrapped with multipart, one part per frame.
+/// Frames are specified as a comma-separated list of frame numbers, e.g. `1,2,5`.
 /// N.B.: tightly coupled to implementation details of OHIF and friends.
 async fn get_frame(
+    State(pypx): State<Arc<PypxReader>>,
+    Path((_study_instance_uid, series_instance_uid, sop_instance_uid, frame_list)): Path<(
+        String,
+        String,
+        String,
+        String,
+    )>,
+    headers: HeaderMap,
+) -> Result<Response, FileError> {
+    let frames = if let Some(frames) = parse_frame_list(&frame_list) {
+        frames
+    } else {
+        let message = format!("Invalid frame list {frame_list:?}: must be comma-separated frame numbers starting from 1");
+        return Ok((StatusCode::BAD_REQUEST, message).into_response());
+    };
+    let accepted = AcceptedType::parse_all(
+        headers
+            .get_all(header::ACCEPT)
+            .into_iter()
+            .filter_map(|value| value.to_str().ok()),
+    );
+    let path = pypx
+        .get_instance_fslocation(&series_instance_uid, &sop_instance_uid)
+        .await?;
+    let (part_content_type, frames_data) = match retrieve_frames(path, frames, accepted).await? {
+        Frames::Found {
+            content_type,
+            frames,
+        } => (content_type, frames),
+        Frames::NotFound => {
+            let message = format!("Instance {sop_instance_uid} does not have frames {frame_list}");
+            return Ok((StatusCode::NOT_FOUND, message).into_response());
+        }
+        Frames::NotAcceptable => {
+            let message = format!(
+                "Frames of instance {sop_instance_uid} are not available in any of the accepted media types"
+            );
+            return Ok((StatusCode::NOT_ACCEPTABLE, message).into_response());
+        }
+    };
+
+    let headers = [
+        (
+            header::ETAG,
+            format!(
+                "\"{}/{}/{}\"",
+                sop_instance_uid, frame_list, part_content_type
+            ),
+        ),
+        (header::VARY, header::ACCEPT.to_string()),
+    ];
+    let multipart = MultipartRelated::new(part_content_type.clone());
+    let parts = futures::stream::iter(frames_data)
+        .map(move |data| Part::from_bytes(part_content_type.clone(), data));
+    Ok((headers, multipart.response(parts)).into_response())
+}
+
+/// Parse a comma-separated list of frame numbers (starting from 1) as frame indexes
+/// (starting from 0).
+fn parse_frame_list(frame_list: &str) -> Option<Vec<u32>> {
+    frame_list
+        .split(',')
+        .map(|frame| frame.trim().parse::<u32>().ok()?.checked_sub(1))
+        .collect()
+}
+
+/// Respond with an instance rendered as an image. For multi-frame instances,
+/// only the first frame is rendered.
+async fn get_rendered_instance(
+    State(pypx): State<Arc<PypxReader>>,
+    Path((_study_instance_uid, series_instance_uid, sop_instance_uid)): Path<(
+        String,
+        String,
+        String,
+    )>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, QueryError> {
+    let options = render_options(&headers, &params)?;
+    let content_type = options.media_type.content_type();
+    let path = pypx
+        .get_instance_fslocation(&series_instance_uid, &sop_instance_uid)
+        .await?;
+    let image = render_frame(path, 0, options).await?;
+    Ok(rendered_response(content_type, image))
+}
+
+/// Respond with a frame of an instance rendered as an image.
+async fn get_rendered_frame(
     State(pypx): State<Arc<PypxReader>>,
     Path((_study_instance_uid, series_instance_uid, sop_instance_uid, frame)): Path<(
         String,
@@ -69,62 +515,188 @@ async fn get_frame(
         String,
         u32,
     )>,
-) -> Result<impl IntoResponse, FileError> {
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, QueryError> {
+    let options = render_options(&headers, &params)?;
+    let content_type = options.media_type.content_type();
     let path = pypx
         .get_instance_fslocation(&series_instance_uid, &sop_instance_uid)
         .await?;
-    let frame_data = encode_frame(path, frame - 1).await?;
+    let image = if let Some(frame) = frame.checked_sub(1) {
+        render_frame(path, frame, options).await?
+    } else {
+        None
+    };
+    Ok(rendered_response(content_type, image))
+}
 
-    // I don't know what UID to use, but here's a list of UIDs which OHIF accpets:
-    // https://github.com/OHIF/Viewers/blob/10ca35d5f497021abd562d457d11818474d02868/platform/core/src/utils/generateAcceptHeader.ts#L39-L55
-    let uid = dicom::dictionary_std::uids::IMPLICIT_VR_LITTLE_ENDIAN;
+/// Respond with every instance of a series rendered as an image, wrapped with multipart.
+/// Instances which cannot be rendered (e.g. structured reports) are omitted.
+async fn get_rendered_series(
+    State(pypx): State<Arc<PypxReader>>,
+    Path((study_instance_uid, series_instance_uid)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, QueryError> {
+    let options = render_options(&headers, &params)?;
+    let content_type = options.media_type.content_type();
+    let files = pypx
+        .get_series_dcm_files(&study_instance_uid, &series_instance_uid)
+        .await?;
+    let parts = futures::stream::iter(files)
+        .then(move |path| render_frame(path, 0, options.clone()))
+        .filter_map(move |rendered| async move {
+            match rendered {
+                Ok(image) => image.map(|bytes| Part::from_bytes(content_type, bytes)),
+                Err(e) => {
+                    event!(Level::WARN, "{:?}", e);
+                    None
+                }
+            }
+        })
+        .boxed();
+    Ok(MultipartRelated::new(content_type).response(parts))
+}
 
-    // transfer-syntax:
-    // https://github.com/RadicalImaging/Static-DICOMWeb/blob/fb045851476facb24143eea7f97b763438059360/packages/static-wado-creator/lib/writer/ImageFrameWriter.js#L27
-    // Content-Type deliminiter is "\r\n\r\n":
-    // https://github.com/cornerstonejs/cornerstone3D/blob/d0d2fac80581648681521e4ddb6a6d9aad2087f9/packages/dicomImageLoader/src/imageLoader/wadors/getPixelData.ts#L64
-    let content_type =
-        format!("Content-Type: application/octet-stream;transfer-syntax={uid}\r\n\r\n");
+/// Respond with a thumbnail of a study, rendered from the middle slice of one of its series.
+async fn get_study_thumbnail(
+    State(pypx): State<Arc<PypxReader>>,
+    Path(study_instance_uid): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, QueryError> {
+    let options = thumbnail_options(&headers, &params)?;
+    let content_type = options.media_type.content_type();
+    let image = pypx
+        .get_study_thumbnail(&study_instance_uid, options)
+        .await?;
+    Ok(rendered_response(content_type, image))
+}
 
-    let headers = [
-        (header::ETAG, format!("\"{}/{}\"", sop_instance_uid, frame)),
-        (header::CONTENT_TYPE, "multipart/related".to_string()),
-    ];
+/// Respond with a thumbnail of a series, rendered from its middle slice.
+async fn get_series_thumbnail(
+    State(pypx): State<Arc<PypxReader>>,
+    Path((_study_instance_uid, series_instance_uid)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, QueryError> {
+    let options = thumbnail_options(&headers, &params)?;
+    let content_type = options.media_type.content_type();
+    let image = pypx
+        .get_series_thumbnail(&series_instance_uid, options)
+        .await?;
+    Ok(rendered_response(content_type, image))
+}
 
-    let size_estimate = MULTIPART_BOUNDARY.len()
-        + content_type.len()
-        + frame_data.len()
-        + MULTIPART_BOUNDARY.len()
-        + 64;
-    let mut body: Vec<u8> = Vec::with_capacity(size_estimate);
-
-    // boundary is separated by "\r\n":
-    // https://github.com/cornerstonejs/cornerstone3D/blob/d0d2fac80581648681521e4ddb6a6d9aad2087f9/packages/dicomImageLoader/src/imageLoader/wadors/getPixelData.ts#L71
-    body.extend(MULTIPART_BOUNDARY);
-    body.extend(b"\r\n");
-    body.extend(content_type.as_bytes());
-    body.extend(frame_data);
-    body.extend(b"\r\n");
-    body.extend(MULTIPART_BOUNDARY);
-    body.extend(b"--");
-
-    let response = (headers, body).into_response();
-    Ok(response)
+/// Respond with a thumbnail of an instance.
+async fn get_instance_thumbnail(
+    State(pypx): State<Arc<PypxReader>>,
+    Path((_study_instance_uid, series_instance_uid, sop_instance_uid)): Path<(
+        String,
+        String,
+        String,
+    )>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, QueryError> {
+    let options = thumbnail_options(&headers, &params)?;
+    let content_type = options.media_type.content_type();
+    let image = pypx
+        .get_instance_thumbnail(&series_instance_uid, &sop_instance_uid, options)
+        .await?;
+    Ok(rendered_response(content_type, image))
+}
+
+/// Parse the query parameters of a request for a thumbnail.
+fn thumbnail_options(
+    headers: &HeaderMap,
+    params: &HashMap<String, String>,
+) -> Result<RenderOptions, QueryError> {
+    let media_type = RenderedMediaType::negotiate(accepted_media_types(headers, params))
+        .ok_or(QueryError::NotAcceptable)?;
+    RenderOptions::parse_thumbnail(params, media_type).map_err(QueryError::from)
+}
+
+/// Parse the query parameters of a request for a rendered resource.
+fn render_options(
+    headers: &HeaderMap,
+    params: &HashMap<String, String>,
+) -> Result<RenderOptions, QueryError> {
+    let media_type = RenderedMediaType::negotiate(accepted_media_types(headers, params))
+        .ok_or(QueryError::NotAcceptable)?;
+    RenderOptions::parse(params, media_type).map_err(QueryError::from)
+}
+
+fn rendered_response(content_type: &'static str, image: Option<Vec<u8>>) -> Response {
+    if let Some(bytes) = image {
+        ([(header::CONTENT_TYPE, content_type)], bytes).into_response()
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+/// The URL of this DICOMweb service, which is needed to produce `RetrieveURL` attributes.
+struct BaseUrl(String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for BaseUrl {
+    type Rejection = HostRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Host(host) = Host::from_request_parts(parts, state).await?;
+        let scheme = parts
+            .headers
+            .get("X-Forwarded-Proto")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("http");
+        // this router is nested, e.g. under "/dicomweb"
+        let path = parts.uri.path();
+        let prefix = parts
+            .extensions
+            .get::<OriginalUri>()
+            .and_then(|original_uri| original_uri.path().strip_suffix(path))
+            .unwrap_or_default();
+        Ok(BaseUrl(format!("{scheme}://{host}{prefix}")))
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
 enum QueryError {
-    #[error("{0}")]
-    BadRequest(&'static str),
+    #[error(transparent)]
+    InvalidParameter(#[from] InvalidQueryParameter),
     #[error(transparent)]
     Err(#[from] FileError),
+    #[error(transparent)]
+    ReadDir(#[from] ReadDirError),
+    #[error("None of the acceptable media types are supported")]
+    NotAcceptable,
 }
 
 impl IntoResponse for QueryError {
     fn into_response(self) -> Response {
         match self {
-            QueryError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg).into_response(),
+            QueryError::InvalidParameter(e) => {
+                (StatusCode::BAD_REQUEST, e.to_string()).into_response()
+            }
             QueryError::Err(e) => e.into_response(),
+            QueryError::ReadDir(e) => e.into_response(),
+            QueryError::NotAcceptable => {
+                (StatusCode::NOT_ACCEPTABLE, self.to_string()).into_response()
+            }
+        }
+    }
+}
+
+/// QIDO-RS response, which includes a warning if the results were truncated by `limit`.
+/// https://dicom.nema.org/medical/dicom/current/output/html/part18.html#sect_8.3.4.4
+impl IntoResponse for Page<Value> {
+    fn into_response(self) -> Response {
+        if self.truncated {
+            let warning = [(header::WARNING, WARNING_ADDITIONAL_RESULTS)];
+            (warning, Json(self.items)).into_response()
+        } else {
+            Json(self.items).into_response()
         }
     }
 }
@@ -147,3 +719,155 @@ impl IntoResponse for ReadDirError {
         (StatusCode::INTERNAL_SERVER_ERROR, format!("{self:?}")).into_response()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::body::{Body, HttpBody};
+    use axum::http::Request;
+    use dicom::core::{PrimitiveValue, VR};
+    use dicom::dictionary_std::{tags, uids};
+    use dicom::object::mem::InMemElement;
+    use dicom::object::meta::FileMetaTableBuilder;
+    use dicom::object::InMemDicomObject;
+    use rstest::*;
+    use tower::ServiceExt;
+
+    #[rstest]
+    #[case("1", Some(vec![0]))]
+    #[case("1,2,5", Some(vec![0, 1, 4]))]
+    #[case("0", None)]
+    #[case("1,,2", None)]
+    #[case("one", None)]
+    fn test_parse_frame_list(#[case] frame_list: &str, #[case] expected: Option<Vec<u32>>) {
+        assert_eq!(parse_frame_list(frame_list), expected)
+    }
+
+    #[rstest]
+    #[case("/studies/1.2.3/series?Modality=MR", false)]
+    #[case("/studies/1.2.3/series?SeriesDescription=SAG*", false)]
+    #[case("/studies/1.2.3/series?InstanceNumber=1", true)]
+    #[case("/studies/1.2.3/series?ProtocolName=MPRAGE", true)]
+    #[case("/series?PatientID=1449c1d&Modality=MR", false)]
+    #[case("/series?SOPInstanceUID=1.2.3", true)]
+    #[case("/studies/1.2.3/series/4.5.6/instances?InstanceNumber=1", false)]
+    #[case("/studies/1.2.3/series/4.5.6/instances?Modality=MR", false)]
+    #[case("/studies/1.2.3/series/4.5.6/instances?PatientID=1449c1d", true)]
+    #[case(
+        "/instances?PatientID=1449c1d&SeriesNumber=5&SOPInstanceUID=1.2.3",
+        false
+    )]
+    #[case("/instances?Rows=512", true)]
+    #[tokio::test]
+    async fn test_unsupported_matching_keys(#[case] uri: &str, #[case] rejected: bool) {
+        let tmp = tempfile::tempdir().unwrap();
+        let request = Request::builder()
+            .uri(uri)
+            .header(header::HOST, "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let response = test_router(tmp.path()).oneshot(request).await.unwrap();
+        assert_eq!(response.status() == StatusCode::BAD_REQUEST, rejected);
+    }
+
+    #[rstest]
+    #[case(None, "0000-")]
+    #[case(Some("7"), "0007-")]
+    #[case(Some("12345"), "12345-")]
+    #[tokio::test]
+    async fn test_store_then_retrieve(
+        #[case] instance_number: Option<&str>,
+        #[case] file_name_prefix: &str,
+    ) {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut obj = InMemDicomObject::from_element_iter([
+            InMemElement::new(tags::PATIENT_ID, VR::LO, PrimitiveValue::from("P1")),
+            InMemElement::new(
+                tags::STUDY_INSTANCE_UID,
+                VR::UI,
+                PrimitiveValue::from("1.2"),
+            ),
+            InMemElement::new(
+                tags::SERIES_INSTANCE_UID,
+                VR::UI,
+                PrimitiveValue::from("1.2.3"),
+            ),
+            InMemElement::new(
+                tags::SOP_INSTANCE_UID,
+                VR::UI,
+                PrimitiveValue::from("1.2.3.4"),
+            ),
+        ]);
+        if let Some(instance_number) = instance_number {
+            obj.put(InMemElement::new(
+                tags::INSTANCE_NUMBER,
+                VR::IS,
+                PrimitiveValue::from(instance_number),
+            ));
+        }
+        let meta = FileMetaTableBuilder::new()
+            .transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN)
+            .media_storage_sop_class_uid(uids::SECONDARY_CAPTURE_IMAGE_STORAGE)
+            .media_storage_sop_instance_uid("1.2.3.4");
+        let mut dcm = Vec::new();
+        obj.with_meta(meta).unwrap().write_all(&mut dcm).unwrap();
+        let mut body = b"--b\r\nContent-Type: application/dicom\r\n\r\n".to_vec();
+        body.extend_from_slice(&dcm);
+        body.extend_from_slice(b"\r\n--b--\r\n");
+
+        let store = Request::builder()
+            .method("POST")
+            .uri("/studies")
+            .header(header::HOST, "localhost")
+            .header(
+                header::CONTENT_TYPE,
+                "multipart/related; type=\"application/dicom\"; boundary=b",
+            )
+            .body(Body::from(body))
+            .unwrap();
+        let response = test_router(tmp.path()).oneshot(store).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let img_dir = tmp.path().join("log/seriesData/1.2.3-img");
+        let file_name = std::fs::read_dir(img_dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .file_name();
+        assert!(file_name.to_string_lossy().starts_with(file_name_prefix));
+
+        let retrieve = Request::builder()
+            .uri("/studies/1.2/series/1.2.3/instances/1.2.3.4")
+            .header(header::HOST, "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let response = test_router(tmp.path()).oneshot(retrieve).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        assert!(bytes.windows(dcm.len()).any(|part| part == dcm));
+    }
+
+    /// Router of an empty `pypx` log and data directory under `dir`.
+    fn test_router(dir: &std::path::Path) -> Router {
+        let log_dir = dir.join("log");
+        std::fs::create_dir_all(log_dir.join("studyData")).unwrap();
+        std::fs::create_dir_all(log_dir.join("seriesData")).unwrap();
+        let data_dir = dir.join("data");
+        let reader = PypxReader::new(
+            &log_dir,
+            data_dir.clone(),
+            data_dir.clone(),
+            0,
+            dir.join("thumbnails"),
+            None,
+        )
+        .unwrap();
+        let writer = PypxWriter::new(&log_dir, data_dir.clone(), data_dir, None);
+        get_router(reader, writer)
+    }
+}
@@ -0,0 +1,195 @@
+//! STOW-RS: storing uploaded DICOM instances.
+//!
+//! https://dicom.nema.org/medical/dicom/current/output/html/part18.html#sect_10.5
+
+use crate::constants::APPLICATION_DICOM;
+use crate::pypx_writer::{PypxWriter, Upload};
+use crate::translate::tag2str;
+use axum::body::Bytes;
+use axum::http::StatusCode;
+use dicom::dictionary_std::tags;
+use futures::Stream;
+use serde_json::{json, Map, Value};
+use tracing::{event, Level};
+
+/// `FailureReason` of an instance which could not be stored.
+/// https://dicom.nema.org/medical/dicom/current/output/html/part04.html#sect_GG.4.2
+const PROCESSING_FAILURE: u16 = 0x0110;
+/// `FailureReason` of a part which is not a DICOM file.
+const CANNOT_UNDERSTAND: u16 = 0xC000;
+
+/// Get the boundary of a `multipart/related; type="application/dicom"` request body.
+/// Returns `None` if the request body is any other media type.
+pub(crate) fn parse_boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';').map(|s| s.trim());
+    if !params.next()?.eq_ignore_ascii_case("multipart/related") {
+        return None;
+    }
+    let mut boundary = None;
+    let mut part_type = None;
+    for param in params {
+        let (name, value) = param.split_once('=')?;
+        let value = value.trim().trim_matches('"');
+        match name.trim().to_ascii_lowercase().as_str() {
+            "boundary" => boundary = Some(value.to_string()),
+            "type" => part_type = Some(value),
+            _ => {}
+        }
+    }
+    // type is mandatory for multipart/related, but some clients omit it
+    if part_type.map(|t| t.eq_ignore_ascii_case(APPLICATION_DICOM)) == Some(false) {
+        return None;
+    }
+    boundary.filter(|b| !b.is_empty())
+}
+
+/// Outcome of a STOW-RS request.
+#[derive(Default)]
+pub(crate) struct StoreResults {
+    /// Items of `ReferencedSOPSequence`.
+    referenced: Vec<Value>,
+    /// Items of `FailedSOPSequence`.
+    failed: Vec<Value>,
+}
+
+impl StoreResults {
+    /// HTTP status of the response: 200 if every instance was stored,
+    /// 202 if only some of them were, otherwise 409.
+    pub fn status(&self) -> StatusCode {
+        if self.failed.is_empty() {
+            StatusCode::OK
+        } else if self.referenced.is_empty() {
+            StatusCode::CONFLICT
+        } else {
+            StatusCode::ACCEPTED
+        }
+    }
+
+    /// Produce the response body, a DICOM JSON object.
+    pub fn to_dicomweb(&self, retrieve_url: Option<&str>) -> Value {
+        let mut dcm = Map::new();
+        if let Some(url) = retrieve_url {
+            dcm.insert(
+                tag2str(tags::RETRIEVE_URL),
+                json!({"vr": "UR", "Value": [url]}),
+            );
+        }
+        if !self.failed.is_empty() {
+            dcm.insert(
+                tag2str(tags::FAILED_SOP_SEQUENCE),
+                json!({"vr": "SQ", "Value": self.failed}),
+            );
+        }
+        dcm.insert(
+            tag2str(tags::REFERENCED_SOP_SEQUENCE),
+            json!({"vr": "SQ", "Value": self.referenced}),
+        );
+        Value::Object(dcm)
+    }
+
+    fn stored(&mut self, upload: &Upload, base_url: &str) {
+        let retrieve_url = format!(
+            "{base_url}/studies/{}/series/{}/instances/{}",
+            upload.study_instance_uid, upload.series_instance_uid, upload.sop_instance_uid
+        );
+        let mut item = referenced_sop(upload);
+        item.insert(
+            tag2str(tags::RETRIEVE_URL),
+            json!({"vr": "UR", "Value": [retrieve_url]}),
+        );
+        self.referenced.push(Value::Object(item));
+    }
+
+    fn failed(&mut self, upload: Option<&Upload>, reason: u16) {
+        let mut item = upload.map(referenced_sop).unwrap_or_default();
+        item.insert(
+            tag2str(tags::FAILURE_REASON),
+            json!({"vr": "US", "Value": [reason]}),
+        );
+        self.failed.push(Value::Object(item));
+    }
+}
+
+/// Store every DICOM file of a `multipart/related` request body.
+///
+/// If `study_instance_uid` is given, instances of other studies are rejected.
+pub(crate) async fn store_instances<S, E>(
+    writer: &PypxWriter,
+    body: S,
+    boundary: String,
+    study_instance_uid: Option<&str>,
+    base_url: &str,
+) -> Result<StoreResults, multer::Error>
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Into<Box<dyn std::error::Error + Send + Sync>> + 'static,
+{
+    let mut multipart = multer::Multipart::new(body, boundary);
+    let mut results = StoreResults::default();
+    while let Some(field) = multipart.next_field().await? {
+        let bytes = field.bytes().await?;
+        let upload = match Upload::parse(bytes) {
+            Ok(upload) => upload,
+            Err(e) => {
+                event!(Level::WARN, "{}", e);
+                results.failed(None, CANNOT_UNDERSTAND);
+                continue;
+            }
+        };
+        if study_instance_uid.is_some_and(|study| study != upload.study_instance_uid) {
+            results.failed(Some(&upload), PROCESSING_FAILURE);
+            continue;
+        }
+        match writer.write(&upload).await {
+            Ok(()) => results.stored(&upload, base_url),
+            Err(e) => {
+                event!(Level::ERROR, "{:?}", e);
+                results.failed(Some(&upload), PROCESSING_FAILURE);
+            }
+        }
+    }
+    Ok(results)
+}
+
+fn referenced_sop(upload: &Upload) -> Map<String, Value> {
+    let mut item = Map::new();
+    item.insert(
+        tag2str(tags::REFERENCED_SOP_CLASS_UID),
+        json!({"vr": "UI", "Value": [upload.sop_class_uid]}),
+    );
+    item.insert(
+        tag2str(tags::REFERENCED_SOP_INSTANCE_UID),
+        json!({"vr": "UI", "Value": [upload.sop_instance_uid]}),
+    );
+    item
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case(
+        "multipart/related; type=\"application/dicom\"; boundary=abc",
+        Some("abc")
+    )]
+    #[case("multipart/related; boundary=\"a b\"", Some("a b"))]
+    #[case("Multipart/Related;Type=application/DICOM;Boundary=xyz", Some("xyz"))]
+    #[case("multipart/related; type=\"application/dicom\"", None)]
+    #[case("multipart/related; type=\"image/jpeg\"; boundary=abc", None)]
+    #[case("multipart/form-data; boundary=abc", None)]
+    fn test_parse_boundary(#[case] content_type: &str, #[case] expected: Option<&str>) {
+        assert_eq!(parse_boundary(content_type).as_deref(), expected)
+    }
+
+    #[rstest]
+    fn test_status() {
+        let mut results = StoreResults::default();
+        assert_eq!(results.status(), StatusCode::OK);
+        results.failed(None, CANNOT_UNDERSTAND);
+        assert_eq!(results.status(), StatusCode::CONFLICT);
+        results.referenced.push(json!({}));
+        assert_eq!(results.status(), StatusCode::ACCEPTED);
+    }
+}
@@ -0,0 +1,58 @@
+//! On-disk cache of rendered thumbnails.
+
+use crate::dicom::is_uid;
+use crate::errors::FileError;
+use crate::rendered::{render_frame, RenderOptions};
+use std::path::PathBuf;
+use tracing::{event, Level};
+
+/// A directory of thumbnails, organized as `{SeriesInstanceUID}/{SOPInstanceUID}_{options}.{ext}`.
+pub(crate) struct ThumbnailCache {
+    dir: PathBuf,
+}
+
+impl ThumbnailCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Get the thumbnail of an instance from the cache, or render it from the DICOM
+    /// file at `dcm_path` and save it to the cache. Failing to write to the cache
+    /// (e.g. if it is read-only) is not an error.
+    pub async fn get_or_render(
+        &self,
+        dcm_path: PathBuf,
+        series_instance_uid: &str,
+        sop_instance_uid: &str,
+        options: RenderOptions,
+    ) -> Result<Option<Vec<u8>>, FileError> {
+        // UIDs come from the request path, so make sure they can't be used to write elsewhere
+        if !(is_uid(series_instance_uid) && is_uid(sop_instance_uid)) {
+            return render_frame(dcm_path, 0, options).await;
+        }
+        let path = self
+            .dir
+            .join(series_instance_uid)
+            .join(options.thumbnail_file_name(sop_instance_uid));
+        match tokio::fs::read(&path).await {
+            Ok(bytes) => return Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => event!(
+                Level::WARN,
+                "Cannot read cached thumbnail {:?}: {:?}",
+                path,
+                e
+            ),
+        }
+        let image = render_frame(dcm_path, 0, options).await?;
+        if let Some(bytes) = &image {
+            let (p, bytes) = (path.clone(), bytes.clone());
+            let written =
+                tokio::task::spawn_blocking(move || pypx::write_atomically(&p, &bytes)).await;
+            if let Err(e) = written.map_err(std::io::Error::from).and_then(|r| r) {
+                event!(Level::WARN, "Cannot cache thumbnail {:?}: {:?}", path, e);
+            }
+        }
+        Ok(image)
+    }
+}
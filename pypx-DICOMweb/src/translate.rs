@@ -1,88 +1,134 @@
 //! Helper functions related to translating to DICOMweb response schemas.
 
-use dicom::dictionary_std::tags;
+use crate::dicom::is_binary;
+use crate::pypx_reader::StudySummary;
+use dicom::core::dictionary::DataDictionaryEntry;
+use dicom::core::{DataDictionary, VR};
+use dicom::dictionary_std::StandardDataDictionary;
 use pypx::*;
-use serde_json::{json, Value};
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
 
 pub fn study_meta_to_dicomweb(data: &StudyDataMeta) -> Value {
-    json!({
-        tag2str(tags::PATIENT_ID): {
-            "vr": "LO",
-            "Value": [
-                data.PatientID
-            ]
-        },
-        tag2str(tags::STUDY_DESCRIPTION): {
-            "vr": "LO",
-            "Value": [
-                data.StudyDescription
-            ]
-        },
-        tag2str(tags::STUDY_DATE): {
-            "vr": "DA",
-            "Value": [
-                data.StudyDate
-            ]
-        },
-        tag2str(tags::STUDY_INSTANCE_UID): {
-            "vr": "UI",
-            "Value": [
-                data.StudyInstanceUID
-            ]
-        },
-        tag2str(tags::PERFORMED_STATION_AE_TITLE): {
-            "vr": "AE",
-            "Value": [
-                data.PerformedStationAETitle
-            ]
-        }
-    })
+    keywords_to_dicomweb([
+        ("PatientID", &data.PatientID),
+        ("StudyDescription", &data.StudyDescription),
+        ("StudyDate", &data.StudyDate),
+        ("StudyInstanceUID", &data.StudyInstanceUID),
+        ("PerformedStationAETitle", &data.PerformedStationAETitle),
+    ])
+}
+
+/// Produce the patient attributes of a study.
+pub fn patient_to_dicomweb(data: &PatientData) -> Value {
+    keywords_to_dicomweb([
+        ("PatientID", &data.PatientID),
+        ("PatientName", &data.PatientName),
+        ("PatientBirthDate", &data.PatientBirthDate),
+        ("PatientSex", &data.PatientSex),
+    ])
+}
+
+/// Produce the number of studies of a patient, for the (non-standard) patient query.
+pub fn patient_studies_to_dicomweb(data: &PatientData) -> Value {
+    let num_studies = data.StudyList.iter().collect::<HashSet<_>>().len();
+    keywords_to_dicomweb([("NumberOfPatientRelatedStudies", num_studies.to_string())])
 }
 
+/// Produce the attributes of a study which are aggregated from its series.
+pub fn study_summary_to_dicomweb(summary: &StudySummary) -> Value {
+    keywords_to_dicomweb([
+        ("ModalitiesInStudy", summary.modalities.as_str()),
+        (
+            "NumberOfStudyRelatedSeries",
+            &summary.num_series.to_string(),
+        ),
+        (
+            "NumberOfStudyRelatedInstances",
+            &summary.num_instances.to_string(),
+        ),
+        ("AccessionNumber", &summary.accession_number),
+        ("StudyTime", &summary.study_time),
+        ("ReferringPhysicianName", &summary.referring_physician_name),
+    ])
+}
+
+/// Produce the attributes of a series from every attribute recorded by pypx.
 pub fn series_meta_to_dicomweb(data: &StudyDataSeriesMeta, num_instances: usize) -> Value {
-    json!({
-        // missing SpecificCharacterSet, CS
-        tag2str(tags::SERIES_DATE): {
-            "vr": "DA",
-            "Value": [ get_pypxed_tag(data, "SeriesDate") ]
-        },
-        tag2str(tags::SERIES_TIME): {
-            "vr": "TM",
-            "Value": [ get_pypxed_tag(data, "SeriesTime") ]
-        },
-        tag2str(tags::MODALITY): {
-            "vr": "MR",
-            "Value": [ get_pypxed_tag(data, "Modality") ]
-        },
-        // missing Manufacturer, LO
-        // missing ReferringPhysicianName, PN
-        tag2str(tags::SERIES_DESCRIPTION): {
-            "vr": "LO",
-            "Value": [ get_pypxed_tag(data, "SeriesDescription") ]
-        },
-        // missing ManufacturerModelName, LO
-        // missing PrivateCreator, CS
-        // missing (0009,1011)
-        // missing (0009,1012)
-        // missing BodyPartExamined, CS
-        // missing ProtocolName, LO
-        tag2str(tags::STUDY_INSTANCE_UID): {
-            "vr": "UI",
-            "Value": [ get_pypxed_tag(data, "StudyInstanceUID") ]
-        },
-        tag2str(tags::SERIES_INSTANCE_UID): {
-            "vr": "UI",
-            "Value": [ get_pypxed_tag(data, "SeriesInstanceUID") ]
-        },
-        tag2str(tags::SERIES_NUMBER): {
-            "vr": "IS",
-            "Value": [ try_parse_int(get_pypxed_tag(data, "SeriesNumber")) ]
-        },
-        tag2str(tags::NUMBER_OF_SERIES_RELATED_INSTANCES): {
-            "vr": "IS",
-            "Value": [ num_instances ]
+    // pypx records the instance-level attributes of one instance of the series,
+    // which do not describe the series as a whole.
+    let series_attributes = data
+        .DICOM
+        .iter()
+        .filter(|(keyword, _)| !matches!(keyword.as_str(), "SOPInstanceUID" | "InstanceNumber"));
+    merge_dicomweb(
+        pypxed_to_dicomweb(series_attributes),
+        keywords_to_dicomweb([
+            ("SeriesInstanceUID", data.SeriesInstanceUID.to_string()),
+            ("NumberOfSeriesRelatedInstances", num_instances.to_string()),
+        ]),
+    )
+}
+
+pub fn instance_to_dicomweb(
+    series: &StudyDataSeriesMeta,
+    instance_number: &str,
+    sop_instance_uid: &str,
+    retrieve_url: &str,
+) -> Value {
+    keywords_to_dicomweb([
+        ("SOPClassUID", get_pypxed_tag(series, "SOPClassUID")),
+        ("SOPInstanceUID", sop_instance_uid),
+        ("InstanceNumber", instance_number),
+        ("RetrieveURL", retrieve_url),
+    ])
+}
+
+/// Produce DICOM JSON from the attributes recorded by pypx, i.e. the `DICOM`
+/// object of a series' JSON file.
+pub fn pypxed_to_dicomweb<'a>(
+    attributes: impl IntoIterator<Item = (&'a String, &'a ValueAndLabel<'a>)>,
+) -> Value {
+    keywords_to_dicomweb(
+        attributes
+            .into_iter()
+            .map(|(keyword, pypxed)| (keyword, &pypxed.value)),
+    )
+}
+
+/// Produce DICOM JSON from pairs of DICOM keyword and value, as pypx would write them.
+///
+/// The tag and VR of every keyword is found in the standard data dictionary.
+/// Keywords which are not in the dictionary, and attributes of binary VRs, are skipped.
+/// An empty value is an attribute without `Value`.
+pub fn keywords_to_dicomweb<K, V>(attributes: impl IntoIterator<Item = (K, V)>) -> Value
+where
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    let dcm: Map<String, Value> = attributes
+        .into_iter()
+        .filter_map(|(keyword, value)| {
+            let entry = StandardDataDictionary.by_name(keyword.as_ref())?;
+            let (tag, vr) = (entry.tag(), entry.vr());
+            if is_binary(vr) {
+                return None;
+            }
+            Some((tag2str(tag), attribute_to_dicomweb(vr, value.as_ref())))
+        })
+        .collect();
+    Value::Object(dcm)
+}
+
+/// Combine the attributes of two DICOM JSON objects.
+pub fn merge_dicomweb(a: Value, b: Value) -> Value {
+    match (a, b) {
+        (Value::Object(mut a), Value::Object(b)) => {
+            a.extend(b);
+            Value::Object(a)
         }
-    })
+        (a, _) => a,
+    }
 }
 
 fn get_pypxed_tag<'a>(data: &'a StudyDataSeriesMeta, tag_name: &str) -> &'a str {
@@ -92,16 +138,60 @@ fn get_pypxed_tag<'a>(data: &'a StudyDataSeriesMeta, tag_name: &str) -> &'a str
         .unwrap_or_default()
 }
 
-fn tag2str(tag: dicom::core::Tag) -> String {
+pub(crate) fn tag2str(tag: dicom::core::Tag) -> String {
     format!("{:04X}{:04X}", tag.0, tag.1)
 }
 
+fn attribute_to_dicomweb(vr: VR, value: &str) -> Value {
+    if value.is_empty() {
+        return json!({ "vr": vr.to_string() });
+    }
+    let values: Vec<_> = if is_multi_valued(vr) {
+        value
+            .split('\\')
+            .map(|v| value_to_dicomweb(vr, v))
+            .collect()
+    } else {
+        vec![value_to_dicomweb(vr, value)]
+    };
+    json!({ "vr": vr.to_string(), "Value": values })
+}
+
+/// Whether `\` separates the values of the VR, rather than being part of the value.
+fn is_multi_valued(vr: VR) -> bool {
+    !matches!(vr, VR::LT | VR::ST | VR::UT | VR::UR)
+}
+
+/// Produce a single value of a DICOM JSON attribute.
+/// https://dicom.nema.org/medical/dicom/current/output/html/part18.html#sect_F.2.3
+fn value_to_dicomweb(vr: VR, value: &str) -> Value {
+    if value.is_empty() {
+        return Value::Null;
+    }
+    match vr {
+        VR::PN => json!({ "Alphabetic": value }),
+        VR::IS | VR::SL | VR::SS | VR::SV | VR::UL | VR::US | VR::UV => try_parse_int(value),
+        VR::DS | VR::FD | VR::FL => try_parse_decimal(value),
+        _ => json!(value),
+    }
+}
+
 fn try_parse_int(num: &str) -> Value {
-    num.parse::<i32>()
+    num.trim()
+        .parse::<i64>()
         .map(|n| json!(n))
         .unwrap_or_else(|_e| json!(num))
 }
 
+fn try_parse_decimal(num: &str) -> Value {
+    num.trim()
+        .parse::<f64>()
+        .ok()
+        .and_then(serde_json::Number::from_f64)
+        .map(Value::Number)
+        .unwrap_or_else(|| json!(num))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -115,6 +205,61 @@ mod test {
         dbg!(thing);
     }
 
+    #[rstest]
+    fn test_series_meta_to_dicomweb() {
+        let pypxed = [
+            ("Modality", "MR"),
+            ("SeriesNumber", "5"),
+            ("ReferringPhysicianName", "DOE^JOHN"),
+            ("BodyPartExamined", ""),
+            ("SOPInstanceUID", "1.2.3.4.5.6"),
+            ("NotAKeyword", "ignored"),
+        ];
+        let series = StudyDataSeriesMeta {
+            SeriesInstanceUID: Cow::from("1.2.3.4"),
+            SeriesBaseDir: Cow::from("/tmp/series"),
+            DICOM: pypxed
+                .into_iter()
+                .map(|(keyword, value)| {
+                    let value_and_label = ValueAndLabel {
+                        value: Cow::from(value),
+                        label: Cow::from(keyword),
+                    };
+                    (keyword.to_string(), value_and_label)
+                })
+                .collect(),
+        };
+        let expected = json!({
+            "00080060": { "vr": "CS", "Value": [ "MR" ] },
+            "00200011": { "vr": "IS", "Value": [ 5 ] },
+            "00080090": { "vr": "PN", "Value": [ { "Alphabetic": "DOE^JOHN" } ] },
+            "00180015": { "vr": "CS" },
+            "0020000E": { "vr": "UI", "Value": [ "1.2.3.4" ] },
+            "00201209": { "vr": "IS", "Value": [ 12 ] }
+        });
+        assert_eq!(series_meta_to_dicomweb(&series, 12), expected)
+    }
+
+    #[rstest]
+    #[case("Modality", "MR", json!({"00080060": {"vr": "CS", "Value": ["MR"]}}))]
+    #[case("ImageType", "ORIGINAL\\PRIMARY\\\\M", json!({"00080008": {"vr": "CS", "Value": ["ORIGINAL", "PRIMARY", null, "M"]}}))]
+    #[case("PatientName", "DOE^JANE", json!({"00100010": {"vr": "PN", "Value": [{"Alphabetic": "DOE^JANE"}]}}))]
+    #[case("PixelSpacing", "0.5\\0.75", json!({"00280030": {"vr": "DS", "Value": [0.5, 0.75]}}))]
+    #[case("SliceThickness", "abc", json!({"00180050": {"vr": "DS", "Value": ["abc"]}}))]
+    #[case("SeriesNumber", " 7", json!({"00200011": {"vr": "IS", "Value": [7]}}))]
+    #[case("Rows", "512", json!({"00280010": {"vr": "US", "Value": [512]}}))]
+    #[case("ImageComments", "a\\b", json!({"00204000": {"vr": "LT", "Value": ["a\\b"]}}))]
+    #[case("StudyTime", "", json!({"00080030": {"vr": "TM"}}))]
+    #[case("PixelData", "binary", json!({}))]
+    #[case("NotAKeyword", "ignored", json!({}))]
+    fn test_keywords_to_dicomweb(
+        #[case] keyword: &str,
+        #[case] value: &str,
+        #[case] expected: Value,
+    ) {
+        assert_eq!(keywords_to_dicomweb([(keyword, value)]), expected)
+    }
+
     #[fixture]
     fn example_study_meta() -> StudyDataMeta<'static> {
         StudyDataMeta {
@@ -1,3 +1,5 @@
 mod models;
+mod writer;
 
 pub use models::*;
+pub use writer::*;
@@ -39,13 +39,87 @@ pub struct InstanceData<'a> {
     pub imageObj: HashMap<Cow<'a, str>, FileStat<'a>>,
 }
 
-/// File's stat metadata.
-/// Not complete.
+/// File's stat metadata, as recorded by `smdb.py` for every packed DICOM file.
 /// https://github.com/FNNDSC/pypx/blob/7619c15f4d2303d6d5ca7c255d81d06c7ab8682b/pypx/smdb.py#L1306-L1317
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FileStat<'a> {
     /// Important! Checked by smdb.py to count how many files are packed so far.
     pub FSlocation: Cow<'a, str>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<Cow<'a, str>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ino: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dev: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nlink: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub atime: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ctime: Option<f64>,
+}
+
+impl<'a> FileStat<'a> {
+    /// Stat metadata of a file which is only known by its location.
+    pub fn new(FSlocation: Cow<'a, str>) -> Self {
+        Self {
+            FSlocation,
+            mode: None,
+            ino: None,
+            dev: None,
+            nlink: None,
+            uid: None,
+            gid: None,
+            size: None,
+            atime: None,
+            mtime: None,
+            ctime: None,
+        }
+    }
+}
+
+/// `log/patientData/{PatientID}.json`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PatientData<'a> {
+    pub PatientID: Cow<'a, str>,
+    #[serde(default)]
+    pub PatientName: Cow<'a, str>,
+    #[serde(default)]
+    pub PatientAge: Cow<'a, str>,
+    #[serde(default)]
+    pub PatientSex: Cow<'a, str>,
+    #[serde(default)]
+    pub PatientBirthDate: Cow<'a, str>,
+    /// `StudyInstanceUID` of every study of this patient.
+    #[serde(default)]
+    pub StudyList: Vec<Cow<'a, str>>,
+}
+
+/// `log/seriesData/{SeriesInstanceUID}-meta.json`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SeriesDataMeta<'a> {
+    pub PatientID: Cow<'a, str>,
+    pub StudyInstanceUID: Cow<'a, str>,
+    pub SeriesInstanceUID: Cow<'a, str>,
+    pub SeriesDescription: Cow<'a, str>,
+    pub SeriesNumber: MaybeU32<'a>,
+    pub SeriesDate: Cow<'a, str>,
+    pub Modality: Cow<'a, str>,
+}
+
+/// `log/seriesData/{SeriesInstanceUID}-pack.json`, which is written once every
+/// file of a series was received and packed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SeriesDataPack {
+    pub seriesPack: bool,
 }
 
 /// Something that is maybe a [u32], but in case it's not valid, is a [str].
@@ -55,3 +129,85 @@ pub enum MaybeU32<'a> {
     U32(u32),
     Str(Cow<'a, str>),
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::*;
+    use serde::de::DeserializeOwned;
+    use serde_json::Value;
+    use std::path::{Path, PathBuf};
+
+    /// Every kind of log file must be read and written back without loss.
+    #[rstest]
+    fn test_round_trip_fixtures() {
+        round_trip_log_dir(&Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/log"));
+    }
+
+    /// Every log file of the example data must be read and written back without loss.
+    /// The example data is downloaded by `example_data/download.sh`.
+    #[rstest]
+    fn test_round_trip_example_data() {
+        let log_dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../example_data/samples/pypx/log");
+        if !log_dir.is_dir() {
+            eprintln!("Skipped: {log_dir:?} does not exist, run example_data/download.sh");
+            return;
+        }
+        round_trip_log_dir(&log_dir);
+    }
+
+    fn round_trip_log_dir(log_dir: &Path) {
+        let paths = json_files_under(log_dir);
+        assert!(!paths.is_empty(), "No log files in {log_dir:?}");
+        for path in paths {
+            let name = path.file_name().unwrap().to_str().unwrap();
+            let parent = path
+                .parent()
+                .unwrap()
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap();
+            let round_trip = if parent == "patientData" {
+                round_trip::<PatientData>
+            } else if parent.ends_with("-series") {
+                round_trip::<StudyDataSeriesMeta>
+            } else if parent.ends_with("-img") {
+                round_trip::<InstanceData>
+            } else if parent == "studyData" {
+                round_trip::<StudyDataMeta>
+            } else if name.ends_with("-meta.json") {
+                round_trip::<SeriesDataMeta>
+            } else if name.ends_with("-pack.json") {
+                round_trip::<SeriesDataPack>
+            } else {
+                panic!("Unknown pypx log file: {path:?}")
+            };
+            let value: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+            assert_eq!(round_trip(&value), value, "{path:?}")
+        }
+    }
+
+    /// Deserialize then serialize a one-member JSON object, e.g. `{"1.2.3": {...}}`.
+    fn round_trip<T: DeserializeOwned + Serialize>(value: &Value) -> Value {
+        let data: HashMap<String, T> = serde_json::from_value(value.clone()).unwrap();
+        serde_json::to_value(data).unwrap()
+    }
+
+    fn json_files_under(dir: &Path) -> Vec<PathBuf> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .flat_map(|path| {
+                if path.is_dir() {
+                    json_files_under(&path)
+                } else if path.extension().is_some_and(|ext| ext == "json") {
+                    vec![path]
+                } else {
+                    vec![]
+                }
+            })
+            .collect()
+    }
+}
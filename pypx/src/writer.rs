@@ -0,0 +1,391 @@
+//! Writes the JSON files of a pypx-organized directory, the same way `smdb.py` does.
+//!
+//! https://github.com/FNNDSC/pypx/blob/7619c15f4d2303d6d5ca7c255d81d06c7ab8682b/pypx/smdb.py
+
+use crate::models::*;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Attributes of a DICOM file as strings, by keyword, e.g. `"PatientID"`.
+/// Sequences and binary attributes should be omitted.
+pub type DicomHeader = HashMap<String, String>;
+
+/// Error writing the JSON files of a DICOM instance.
+#[derive(thiserror::Error, Debug)]
+pub enum WriteError {
+    #[error("Missing attribute {0}")]
+    MissingAttribute(&'static str),
+    #[error("Invalid file location {0:?}")]
+    InvalidLocation(String),
+    #[error("Error writing file {0:?}: {1}")]
+    IO(PathBuf, #[source] std::io::Error),
+    #[error("Existing file {0:?} is not valid: {1}")]
+    Malformed(PathBuf, #[source] serde_json::Error),
+}
+
+/// Number of locks guarding the `patientData` files, see [PypxLogWriter::update_patient_data].
+const PATIENT_DATA_LOCKS: usize = 64;
+
+/// Writer of the `studyData` and `seriesData` JSON files of a pypx `log` directory.
+#[derive(Debug, Clone)]
+pub struct PypxLogWriter {
+    study_data_dir: PathBuf,
+    series_data_dir: PathBuf,
+    patient_data_dir: PathBuf,
+    patient_data_locks: Arc<[Mutex<()>]>,
+}
+
+impl PypxLogWriter {
+    pub fn new(log_dir: &Path) -> Self {
+        Self {
+            study_data_dir: log_dir.join("studyData"),
+            series_data_dir: log_dir.join("seriesData"),
+            patient_data_dir: log_dir.join("patientData"),
+            patient_data_locks: (0..PATIENT_DATA_LOCKS).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    /// Write the JSON files describing a DICOM instance, which was stored at `fs_location`
+    /// (as seen by other pypx programs):
+    ///
+    /// - `patientData/{PatientID}.json` (if `PatientID` is known)
+    /// - `studyData/{StudyInstanceUID}-meta.json`
+    /// - `studyData/{StudyInstanceUID}-series/{SeriesInstanceUID}-meta.json`
+    /// - `seriesData/{SeriesInstanceUID}-meta.json`
+    /// - `seriesData/{SeriesInstanceUID}-img/{file_name}.json`
+    ///
+    /// Every file is written atomically, so concurrent readers never see a partial file.
+    pub fn write(&self, header: &DicomHeader, fs_location: &str) -> Result<(), WriteError> {
+        let study = get_required(header, "StudyInstanceUID")?;
+        let series = get_required(header, "SeriesInstanceUID")?;
+        get_required(header, "SOPInstanceUID")?;
+        let (series_base_dir, file_name) = fs_location
+            .rsplit_once('/')
+            .filter(|(_, file_name)| !file_name.is_empty())
+            .ok_or_else(|| WriteError::InvalidLocation(fs_location.to_string()))?;
+
+        let patient_id = get(header, "PatientID");
+        if is_valid_file_name(patient_id) {
+            let path = self.patient_data_dir.join(format!("{patient_id}.json"));
+            self.update_patient_data(&path, header)?;
+        }
+
+        let path = self.study_data_dir.join(format!("{study}-meta.json"));
+        write_1member_json_file(&path, study, &StudyDataMeta::from_header(header))?;
+
+        let path = self
+            .study_data_dir
+            .join(format!("{study}-series"))
+            .join(format!("{series}-meta.json"));
+        let study_series_meta = StudyDataSeriesMeta::from_header(header, series_base_dir);
+        write_1member_json_file(&path, series, &study_series_meta)?;
+
+        let path = self.series_data_dir.join(format!("{series}-meta.json"));
+        write_1member_json_file(&path, series, &SeriesDataMeta::from_header(header))?;
+
+        let path = self
+            .series_data_dir
+            .join(format!("{series}-img"))
+            .join(format!("{file_name}.json"));
+        let instance_data = InstanceData::from_header(header, fs_location);
+        write_1member_json_file(&path, series, &instance_data)
+    }
+
+    /// Write `patientData/{PatientID}.json`, adding the study to the `StudyList`
+    /// of the patient if it was already written.
+    ///
+    /// Updates of the same file by clones of this writer are serialized, so that concurrent
+    /// writes of different studies of a patient don't lose any of them. An existing file
+    /// which can't be parsed is an error rather than being overwritten.
+    fn update_patient_data(&self, path: &Path, header: &DicomHeader) -> Result<(), WriteError> {
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        let lock = &self.patient_data_locks[hasher.finish() as usize % PATIENT_DATA_LOCKS];
+        // the lock guards no data, so it is still usable if another writer panicked
+        let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        let existing: Option<HashMap<String, PatientData>> = match std::fs::read(path) {
+            Ok(data) => Some(
+                serde_json::from_slice(&data)
+                    .map_err(|e| WriteError::Malformed(path.to_path_buf(), e))?,
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(WriteError::IO(path.to_path_buf(), e)),
+        };
+        let mut patient = PatientData::from_header(header);
+        let study_list = existing
+            .and_then(|data| data.into_values().next())
+            .map(|existing| existing.StudyList)
+            .unwrap_or_default();
+        let study = get(header, "StudyInstanceUID");
+        if study_list.iter().any(|s| s == study) {
+            patient.StudyList = study_list;
+        } else {
+            patient.StudyList = study_list.into_iter().chain(patient.StudyList).collect();
+        }
+        write_1member_json_file(path, &patient.PatientID, &patient)
+    }
+}
+
+impl<'a> PatientData<'a> {
+    /// Produce the content of `patientData/{PatientID}.json` for a patient's first study.
+    pub fn from_header(header: &'a DicomHeader) -> Self {
+        Self {
+            PatientID: get(header, "PatientID").into(),
+            PatientName: get(header, "PatientName").into(),
+            PatientAge: get(header, "PatientAge").into(),
+            PatientSex: get(header, "PatientSex").into(),
+            PatientBirthDate: get(header, "PatientBirthDate").into(),
+            StudyList: vec![get(header, "StudyInstanceUID").into()],
+        }
+    }
+}
+
+impl<'a> StudyDataMeta<'a> {
+    /// Produce the content of `studyData/{StudyInstanceUID}-meta.json`.
+    pub fn from_header(header: &'a DicomHeader) -> Self {
+        Self {
+            PatientID: get(header, "PatientID").into(),
+            StudyDescription: get(header, "StudyDescription").into(),
+            StudyDate: get(header, "StudyDate").into(),
+            StudyInstanceUID: get(header, "StudyInstanceUID").into(),
+            PerformedStationAETitle: get(header, "PerformedStationAETitle").into(),
+        }
+    }
+}
+
+impl<'a> StudyDataSeriesMeta<'a> {
+    /// Produce the content of `studyData/{StudyInstanceUID}-series/{SeriesInstanceUID}-meta.json`.
+    pub fn from_header(header: &'a DicomHeader, series_base_dir: &'a str) -> Self {
+        Self {
+            SeriesInstanceUID: get(header, "SeriesInstanceUID").into(),
+            SeriesBaseDir: series_base_dir.into(),
+            DICOM: header
+                .iter()
+                .map(|(keyword, value)| {
+                    let value_and_label = ValueAndLabel {
+                        value: value.into(),
+                        label: keyword.into(),
+                    };
+                    (keyword.to_string(), value_and_label)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl<'a> InstanceData<'a> {
+    /// Produce the content of `seriesData/{SeriesInstanceUID}-img/{file_name}.json`
+    /// for a DICOM file stored at `fs_location`.
+    pub fn from_header(header: &'a DicomHeader, fs_location: &'a str) -> Self {
+        let file_name = fs_location.rsplit('/').next().unwrap_or(fs_location);
+        Self {
+            PatientID: get(header, "PatientID").into(),
+            StudyInstanceUID: get(header, "StudyInstanceUID").into(),
+            SeriesInstanceUID: get(header, "SeriesInstanceUID").into(),
+            SeriesDescription: get(header, "SeriesDescription").into(),
+            SeriesNumber: MaybeU32::parse(get(header, "SeriesNumber")),
+            SeriesDate: get(header, "SeriesDate").into(),
+            Modality: get(header, "Modality").into(),
+            outputFile: file_name.into(),
+            imageObj: HashMap::from([(Cow::from(file_name), FileStat::new(fs_location.into()))]),
+        }
+    }
+}
+
+impl<'a> SeriesDataMeta<'a> {
+    /// Produce the content of `seriesData/{SeriesInstanceUID}-meta.json`.
+    pub fn from_header(header: &'a DicomHeader) -> Self {
+        Self {
+            PatientID: get(header, "PatientID").into(),
+            StudyInstanceUID: get(header, "StudyInstanceUID").into(),
+            SeriesInstanceUID: get(header, "SeriesInstanceUID").into(),
+            SeriesDescription: get(header, "SeriesDescription").into(),
+            SeriesNumber: MaybeU32::parse(get(header, "SeriesNumber")),
+            SeriesDate: get(header, "SeriesDate").into(),
+            Modality: get(header, "Modality").into(),
+        }
+    }
+}
+
+impl<'a> MaybeU32<'a> {
+    /// Parse a value such as `SeriesNumber`, keeping it as a string if it is not a number.
+    pub fn parse(value: &'a str) -> Self {
+        value
+            .trim()
+            .parse()
+            .map(MaybeU32::U32)
+            .unwrap_or_else(|_| MaybeU32::Str(value.into()))
+    }
+}
+
+/// Whether a value, e.g. a `PatientID`, can be used as a file name.
+fn is_valid_file_name(value: &str) -> bool {
+    !value.is_empty() && !value.starts_with('.') && !value.contains('/')
+}
+
+fn get<'a>(header: &'a DicomHeader, keyword: &str) -> &'a str {
+    header.get(keyword).map(|s| s.as_str()).unwrap_or_default()
+}
+
+fn get_required<'a>(header: &'a DicomHeader, keyword: &'static str) -> Result<&'a str, WriteError> {
+    header
+        .get(keyword)
+        .map(|s| s.as_str())
+        .filter(|s| !s.is_empty())
+        .ok_or(WriteError::MissingAttribute(keyword))
+}
+
+/// Write a JSON file which has one member, e.g. `{"1.2.3": {...}}`.
+fn write_1member_json_file<T: Serialize>(
+    path: &Path,
+    key: &str,
+    value: &T,
+) -> Result<(), WriteError> {
+    let data = serde_json::to_vec(&HashMap::from([(key, value)]))
+        .expect("Serialization to JSON should not fail");
+    write_atomically(path, &data).map_err(|e| WriteError::IO(path.to_path_buf(), e))
+}
+
+/// Write to a temporary file then rename it, so that concurrent readers
+/// never read a partially written file. Missing parent directories are created.
+pub fn write_atomically(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let tmp = path.with_extension(format!("{}-{n}.tmp", std::process::id()));
+    std::fs::write(&tmp, bytes)?;
+    let renamed = std::fs::rename(&tmp, path);
+    if renamed.is_err() {
+        std::fs::remove_file(&tmp).ok();
+    }
+    renamed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case("5", MaybeU32::U32(5))]
+    #[case(" 12 ", MaybeU32::U32(12))]
+    #[case("1a", MaybeU32::Str("1a".into()))]
+    fn test_parse_maybe_u32(#[case] value: &str, #[case] expected: MaybeU32) {
+        assert_eq!(MaybeU32::parse(value), expected)
+    }
+
+    #[rstest]
+    fn test_write(example_header: DicomHeader) {
+        let tmp = tempfile::tempdir().unwrap();
+        let log_dir = tmp.path();
+        let writer = PypxLogWriter::new(log_dir);
+        let fs_location = "/home/dicom/data/P123-DOE_JANE-19900101/MR_Brain-ACC1-20230101/00003-T1_axial-2cbcc78/0007-1.2.3.4.5.6.dcm";
+        writer.write(&example_header, fs_location).unwrap();
+
+        let study: HashMap<String, StudyDataMeta> =
+            read_json(log_dir.join("studyData/1.2.3.4-meta.json"));
+        assert_eq!(study["1.2.3.4"].PatientID, "P123");
+        let series: HashMap<String, StudyDataSeriesMeta> =
+            read_json(log_dir.join("studyData/1.2.3.4-series/1.2.3.4.5-meta.json"));
+        let series = &series["1.2.3.4.5"];
+        assert_eq!(
+            series.SeriesBaseDir,
+            "/home/dicom/data/P123-DOE_JANE-19900101/MR_Brain-ACC1-20230101/00003-T1_axial-2cbcc78"
+        );
+        assert_eq!(series.DICOM["Modality"].value, "MR");
+        let instance: HashMap<String, InstanceData> =
+            read_json(log_dir.join("seriesData/1.2.3.4.5-img/0007-1.2.3.4.5.6.dcm.json"));
+        let instance = &instance["1.2.3.4.5"];
+        assert_eq!(instance.SeriesNumber, MaybeU32::U32(3));
+        assert_eq!(instance.outputFile, "0007-1.2.3.4.5.6.dcm");
+        assert_eq!(
+            instance.imageObj["0007-1.2.3.4.5.6.dcm"].FSlocation,
+            fs_location
+        );
+        assert!(log_dir.join("seriesData/1.2.3.4.5-meta.json").is_file());
+
+        let mut other_study = example_header.clone();
+        other_study.insert("StudyInstanceUID".to_string(), "1.2.3.9".to_string());
+        writer.write(&other_study, fs_location).unwrap();
+        writer.write(&example_header, fs_location).unwrap();
+        let patient: HashMap<String, PatientData> =
+            read_json(log_dir.join("patientData/P123.json"));
+        assert_eq!(patient["P123"].StudyList, vec!["1.2.3.4", "1.2.3.9"]);
+    }
+
+    #[rstest]
+    fn test_write_same_patient_concurrently(example_header: DicomHeader) {
+        let tmp = tempfile::tempdir().unwrap();
+        let writer = PypxLogWriter::new(tmp.path());
+        let studies: Vec<String> = (0..16).map(|i| format!("1.2.3.{i}")).collect();
+        std::thread::scope(|scope| {
+            for study in &studies {
+                let mut header = example_header.clone();
+                header.insert("StudyInstanceUID".to_string(), study.to_string());
+                let writer = writer.clone();
+                scope.spawn(move || writer.write(&header, "/data/0001-1.2.3.4.5.6.dcm").unwrap());
+            }
+        });
+        let patient: HashMap<String, PatientData> =
+            read_json(tmp.path().join("patientData/P123.json"));
+        let mut actual = patient["P123"].StudyList.clone();
+        actual.sort_unstable();
+        let mut expected: Vec<_> = studies.iter().map(|s| Cow::from(s.as_str())).collect();
+        expected.sort_unstable();
+        assert_eq!(actual, expected);
+    }
+
+    #[rstest]
+    fn test_write_malformed_patient_data(example_header: DicomHeader) {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("patientData/P123.json");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "{\"P123\": ").unwrap();
+        let writer = PypxLogWriter::new(tmp.path());
+        let result = writer.write(&example_header, "/data/0001-1.2.3.4.5.6.dcm");
+        assert!(matches!(result, Err(WriteError::Malformed(p, _)) if p == path));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{\"P123\": ");
+    }
+
+    #[rstest]
+    fn test_write_missing_uid(mut example_header: DicomHeader) {
+        example_header.remove("SeriesInstanceUID");
+        let writer = PypxLogWriter::new(Path::new("/nonexistent"));
+        let result = writer.write(&example_header, "/data/0001-1.2.3.4.5.6.dcm");
+        assert!(matches!(
+            result,
+            Err(WriteError::MissingAttribute("SeriesInstanceUID"))
+        ))
+    }
+
+    fn read_json<T: serde::de::DeserializeOwned>(path: PathBuf) -> T {
+        serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap()
+    }
+
+    #[fixture]
+    fn example_header() -> DicomHeader {
+        [
+            ("PatientID", "P123"),
+            ("StudyDescription", "MR Brain"),
+            ("StudyDate", "20230101"),
+            ("StudyInstanceUID", "1.2.3.4"),
+            ("SeriesInstanceUID", "1.2.3.4.5"),
+            ("SeriesNumber", "3"),
+            ("SeriesDescription", "T1 axial"),
+            ("Modality", "MR"),
+            ("SOPInstanceUID", "1.2.3.4.5.6"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+    }
+}